@@ -0,0 +1,41 @@
+use calculator::parser::{parse_descent, parse_pest};
+use criterion::{BenchmarkId, Criterion, black_box, criterion_group, criterion_main};
+
+// A long flat chain like `1 + 2.5 * (3 - 4) / 5 + ...`.
+fn long_chain(terms: usize) -> String {
+    let mut source = String::from("1");
+    for i in 0..terms {
+        let op = ["+", "-", "*", "/"][i % 4];
+        match i % 3 {
+            0 => source.push_str(&format!(" {} {}", op, i)),
+            1 => source.push_str(&format!(" {} {}.5", op, i)),
+            _ => source.push_str(&format!(" {} ({} - {})", op, i, i + 1)),
+        }
+    }
+    source
+}
+
+fn deeply_nested(depth: usize) -> String {
+    format!("{}1{}", "(1 + ".repeat(depth), ")".repeat(depth))
+}
+
+fn bench_parsers(c: &mut Criterion) {
+    let inputs = [
+        ("chain_1k", long_chain(1_000)),
+        ("chain_10k", long_chain(10_000)),
+        ("nested_200", deeply_nested(200)),
+    ];
+    let mut group = c.benchmark_group("parse");
+    for (name, source) in &inputs {
+        group.bench_with_input(BenchmarkId::new("pest", name), source, |b, s| {
+            b.iter(|| parse_pest(black_box(s)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("descent", name), source, |b, s| {
+            b.iter(|| parse_descent(black_box(s)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parsers);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+use calculator::Compile;
+use calculator::compiler::register::machine::RegisterVM;
+use calculator::compiler::register::program::Compiler as RegisterCompiler;
+use calculator::compiler::vm::bytecode::Interpreter as BytecodeCompiler;
+use calculator::compiler::vm::verifier;
+use calculator::compiler::vm::vm::VM;
+use criterion::{BenchmarkId, Criterion, black_box, criterion_group, criterion_main};
+
+// Mixed Int and Float arithmetic with negated, parenthesised operands.
+fn mixed(groups: usize) -> String {
+    let mut source = String::from("1");
+    for i in 0..groups {
+        let op = ["+", "-", "*", "/"][i % 4];
+        let group = format!("(-({} + {}.5) * ({} - {}))", i, i, i + 7, i % 5);
+        source.push_str(&format!(" {} {}", op, group));
+    }
+    source
+}
+
+// One long flat chain, which keeps the stack VM's stack shallow.
+fn long_chain(terms: usize) -> String {
+    let mut source = String::from("1");
+    for i in 0..terms {
+        let op = ["+", "-", "*", "/"][i % 4];
+        source.push_str(&format!(" {} {}", op, i % 9 + 1));
+    }
+    source
+}
+
+// Right-nested operands, which the stack VM has to keep on its stack.
+fn nested(depth: usize) -> String {
+    format!("{}1{}", "(2 * ".repeat(depth), ")".repeat(depth))
+}
+
+fn bench_vms(c: &mut Criterion) {
+    let inputs = [
+        ("mixed_1k", mixed(1_000)),
+        ("chain_10k", long_chain(10_000)),
+        ("nested_400", nested(400)),
+    ];
+    let mut group = c.benchmark_group("execute");
+    for (name, source) in &inputs {
+        // Only execution is measured; both compile once up front. `VM::execute`
+        // takes its bytecode by value, so the stack timings include a clone.
+        let bytecode = BytecodeCompiler::from_source(source).unwrap().unwrap();
+        let program = RegisterCompiler::from_source(source).unwrap().unwrap();
+        group.bench_with_input(BenchmarkId::new("stack", name), &bytecode, |b, code| {
+            b.iter(|| VM::execute(black_box(code.clone())).unwrap())
+        });
+        // A single run that decodes up front, as `VM::new` does, where
+        // `VM::execute` decodes as it goes.
+        group.bench_with_input(BenchmarkId::new("stack_new", name), &bytecode, |b, code| {
+            b.iter(|| {
+                let mut vm = VM::new(black_box(code.clone())).unwrap();
+                vm.run().unwrap();
+                vm.result()
+            })
+        });
+        // Decodes once, then times only the dispatch loop.
+        group.bench_with_input(BenchmarkId::new("stack_run", name), &bytecode, |b, code| {
+            let mut vm = VM::new(code.clone()).unwrap();
+            b.iter(|| {
+                vm.run().unwrap();
+                vm.result()
+            })
+        });
+        // The verifier's share of `VM::execute`.
+        group.bench_with_input(BenchmarkId::new("verify", name), &bytecode, |b, code| {
+            b.iter(|| verifier::check(black_box(code)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("register", name), &program, |b, code| {
+            b.iter(|| RegisterVM::execute(black_box(code)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_vms);
+criterion_main!(benches);
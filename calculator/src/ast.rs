@@ -1,10 +1,16 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum Operator {
+    #[serde(rename = "+")]
     Plus,
+    #[serde(rename = "-")]
     Minus,
+    #[serde(rename = "*")]
     Multiply,
+    #[serde(rename = "/")]
     Divide,
 }
 
@@ -20,6 +26,14 @@ impl<'a> From<&'a str> for Operator {
     }
 }
 
+impl Operator {
+    // All binary operators currently share one precedence level and associate
+    // to the left, see `Expr` in grammar.pest.
+    pub fn precedence(&self) -> u8 {
+        1
+    }
+}
+
 impl fmt::Display for Operator {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self {
@@ -31,10 +45,10 @@ impl fmt::Display for Operator {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum Node {
     Int(i32),
-    Float(f64),
+    Float(#[serde(with = "crate::dump::json_float")] f64),
     UnaryExpr {
         op: Operator,
         child: Box<Node>,
@@ -46,13 +60,716 @@ pub enum Node {
     },
 }
 
-impl std::fmt::Display for Node {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+// Dropping recursively would overflow the stack on long chains, which nest
+// as deep as they are long, so the tree is taken apart one node at a time.
+impl Drop for Node {
+    fn drop(&mut self) {
+        let mut stack = Vec::new();
+        self.detach_operands(&mut stack);
+        while let Some(mut node) = stack.pop() {
+            node.detach_operands(&mut stack);
+        }
+    }
+}
+
+// Compared and cloned with an explicit stack too, as derived impls recurse.
+impl PartialEq for Node {
+    fn eq(&self, other: &Node) -> bool {
+        let mut stack = vec![(self, other)];
+        while let Some(pair) = stack.pop() {
+            match pair {
+                (Node::Int(a), Node::Int(b)) if a == b => {}
+                (Node::Float(a), Node::Float(b)) if a == b => {}
+                (
+                    Node::UnaryExpr { op, child },
+                    Node::UnaryExpr {
+                        op: other_op,
+                        child: other_child,
+                    },
+                ) if op == other_op => stack.push((child, other_child)),
+                (
+                    Node::BinaryExpr { op, lhs, rhs },
+                    Node::BinaryExpr {
+                        op: other_op,
+                        lhs: other_lhs,
+                        rhs: other_rhs,
+                    },
+                ) if op == other_op => {
+                    stack.push((rhs, other_rhs));
+                    stack.push((lhs, other_lhs));
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl Clone for Node {
+    fn clone(&self) -> Node {
+        // Rebuilds each node from its operands' copies, left on a stack.
+        struct Copier(Vec<Node>);
+
+        impl Copier {
+            fn pop(&mut self) -> Node {
+                self.0.pop().expect("operand was copied")
+            }
+        }
+
+        impl Visitor for Copier {
+            fn visit_int(&mut self, value: i32) -> Result<()> {
+                self.0.push(Node::Int(value));
+                Ok(())
+            }
+
+            fn visit_float(&mut self, value: f64) -> Result<()> {
+                self.0.push(Node::Float(value));
+                Ok(())
+            }
+
+            fn visit_unary(&mut self, op: Operator, _child: &Node) -> Result<()> {
+                let child = Box::new(self.pop());
+                self.0.push(Node::UnaryExpr { op, child });
+                Ok(())
+            }
+
+            fn visit_binary(&mut self, op: Operator, _lhs: &Node, _rhs: &Node) -> Result<()> {
+                let rhs = Box::new(self.pop());
+                let lhs = Box::new(self.pop());
+                self.0.push(Node::BinaryExpr { op, lhs, rhs });
+                Ok(())
+            }
+        }
+
+        let mut copier = Copier(Vec::new());
+        walk(&mut copier, self).expect("copying can't fail");
+        copier.pop()
+    }
+}
+
+// Where a node came from: its byte range in the source, and the line and
+// column (in bytes, from 1) it starts at. Parentheses around a node are not
+// part of its span.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+// Collects the spans of nodes as a parser builds them, i.e. in post-order,
+// which is also the order the bytecode compiler emits their instructions in.
+pub(crate) struct Spans {
+    // The offset each line starts at.
+    lines: Vec<usize>,
+    spans: Vec<Span>,
+}
+
+impl Spans {
+    pub fn new(source: &str) -> Self {
+        let lines = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            lines,
+            spans: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, start: usize, end: usize) {
+        let line = self
+            .lines
+            .partition_point(|&line_start| line_start <= start);
+        self.spans.push(Span {
+            start,
+            end,
+            line,
+            column: start - self.lines[line - 1] + 1,
+        });
+    }
+
+    // The span of the node built last.
+    pub fn last(&self) -> Span {
+        *self.spans.last().expect("no node has been built")
+    }
+
+    pub fn into_vec(self) -> Vec<Span> {
+        self.spans
+    }
+}
+
+// Where a node is printed, used to decide whether it needs parentheses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    Top,
+    UnaryOperand,
+    Lhs(Operator),
+    Rhs(Operator),
+}
+
+impl Node {
+    // The number of nodes in the tree.
+    pub fn size(&self) -> usize {
+        let mut size = 0;
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            size += 1;
+            match node {
+                Node::Int(_) | Node::Float(_) => {}
+                Node::UnaryExpr { child, .. } => stack.push(child),
+                Node::BinaryExpr { lhs, rhs, .. } => {
+                    stack.push(lhs);
+                    stack.push(rhs);
+                }
+            }
+        }
+        size
+    }
+
+    // Moves the node out of `slot`, leaving a literal behind. `Node`
+    // implements `Drop`, so a pattern can't move its operands out.
+    pub fn take(slot: &mut Node) -> Node {
+        std::mem::replace(slot, Node::Int(0))
+    }
+
+    // Moves operands that have operands of their own onto `stack`.
+    fn detach_operands(&mut self, stack: &mut Vec<Node>) {
+        match self {
+            Node::Int(_) | Node::Float(_) => {}
+            Node::UnaryExpr { child, .. } => {
+                if !child.is_literal() {
+                    stack.push(Node::take(child));
+                }
+            }
+            Node::BinaryExpr { lhs, rhs, .. } => {
+                for operand in [lhs, rhs] {
+                    if !operand.is_literal() {
+                        stack.push(Node::take(operand));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn is_literal(&self) -> bool {
+        matches!(self, Node::Int(_) | Node::Float(_))
+    }
+
+    pub fn needs_parens(&self, position: Position) -> bool {
+        match (self, position) {
+            (_, Position::Top) => false,
+            // Negative literals print with a leading `-`, like a unary
+            // expression.
+            (Node::Int(_) | Node::Float(_), Position::Rhs(_) | Position::UnaryOperand) => {
+                is_negative_literal(self)
+            }
+            (Node::Int(_) | Node::Float(_), _) => false,
+            // The operand of a unary operator is a `Term` in the grammar.
+            (_, Position::UnaryOperand) => true,
+            // Only the first operand of a chain may be a unary expression.
+            (Node::UnaryExpr { .. }, Position::Lhs(_)) => false,
+            (Node::UnaryExpr { .. }, Position::Rhs(_)) => true,
+            (Node::BinaryExpr { op: inner, .. }, Position::Lhs(outer)) => {
+                inner.precedence() < outer.precedence()
+            }
+            (Node::BinaryExpr { op: inner, .. }, Position::Rhs(outer)) => {
+                inner.precedence() <= outer.precedence()
+            }
+        }
+    }
+}
+
+// Literal digits as spelled in source. There is no negative literal syntax:
+// both parsers fold a parenthesized minus applied to digits, `(-5)`, into a
+// negative literal, which is how `i32::MIN` is spelled.
+pub fn parse_int_literal(digits: &str, negative: bool) -> Result<i32> {
+    let value = digits.parse::<i64>()?;
+    Ok(i32::try_from(if negative { -value } else { value })?)
+}
+
+pub fn parse_float_literal(digits: &str, negative: bool) -> Result<f64> {
+    let value = digits.parse::<f64>()?;
+    Ok(if negative { -value } else { value })
+}
+
+fn is_negative_literal(node: &Node) -> bool {
+    match node {
+        Node::Int(n) => *n < 0,
+        Node::Float(n) => n.is_sign_negative() && !n.is_nan(),
+        _ => false,
+    }
+}
+
+fn write_float_digits(f: &mut fmt::Formatter, n: f64) -> fmt::Result {
+    if n.is_infinite() {
+        // The smallest power of ten past `f64::MAX`, which parses as infinity.
+        write!(f, "1{}.0", "0".repeat(309))
+    } else if n.fract() == 0.0 {
+        // `Display` never uses exponents, but drops the fraction of whole
+        // numbers, which would read back as an Int.
+        write!(f, "{}.0", n)
+    } else {
+        write!(f, "{}", n)
+    }
+}
+
+// Negative literals are always parenthesized, or they would read back as a
+// unary minus.
+fn write_lossless_literal(f: &mut fmt::Formatter, node: &Node) -> fmt::Result {
+    match node {
+        // NaN has no literal, so it is printed as an expression evaluating
+        // to one; this is the only output that doesn't read back unchanged.
+        Node::Float(n) if n.is_nan() => write!(f, "(0.0 / 0.0)"),
+        Node::Float(n) if n.is_sign_negative() => {
+            write!(f, "(-")?;
+            write_float_digits(f, n.abs())?;
+            write!(f, ")")
+        }
+        Node::Float(n) => write_float_digits(f, *n),
+        Node::Int(n) if *n < 0 => write!(f, "(-{})", n.unsigned_abs()),
+        Node::Int(n) => write!(f, "{}", n),
+        _ => unreachable!("not a literal"),
+    }
+}
+
+impl Node {
+    // Prints the node so that parsing the output yields an equal node, NaN
+    // aside, unlike `Display`, which favours readable literals.
+    pub fn lossless(&self) -> Lossless<'_> {
+        self.lossless_at(Position::Top)
+    }
+
+    // Like `lossless`, with parentheses if needed at `position`.
+    pub fn lossless_at(&self, position: Position) -> Lossless<'_> {
+        Lossless(self, position)
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, lossless: bool) -> fmt::Result {
         match &self {
+            Node::Int(_) | Node::Float(_) if lossless => write_lossless_literal(f, self),
             Node::Int(n) => write!(f, "{}", n),
             Node::Float(n) => write!(f, "{}", n),
-            Node::UnaryExpr { op, child } => write!(f, "{}{}", op, child),
-            Node::BinaryExpr { op, lhs, rhs } => write!(f, "{} {} {}", lhs, op, rhs),
+            Node::UnaryExpr { op, child } => {
+                write!(f, "{}", op)?;
+                child.write_at(f, Position::UnaryOperand, lossless)
+            }
+            Node::BinaryExpr { .. } => {
+                // Down the chain of left operands in a loop, so only
+                // parentheses in the source nest the recursion.
+                let mut chain = Vec::new();
+                let mut first = self;
+                while let Node::BinaryExpr { op, lhs, rhs } = first {
+                    chain.push((*op, rhs));
+                    first = lhs;
+                    if first.needs_parens(Position::Lhs(*op)) {
+                        break;
+                    }
+                }
+                let (op, _) = chain.last().expect("self is a binary expression");
+                first.write_at(f, Position::Lhs(*op), lossless)?;
+                for (op, rhs) in chain.iter().rev() {
+                    write!(f, " {} ", op)?;
+                    rhs.write_at(f, Position::Rhs(*op), lossless)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn write_at(&self, f: &mut fmt::Formatter, position: Position, lossless: bool) -> fmt::Result {
+        // Lossless literals bring their own parentheses.
+        if !self.needs_parens(position) || (lossless && self.is_literal()) {
+            return self.write(f, lossless);
+        }
+        write!(f, "(")?;
+        match self {
+            // `(-1)` would read back as a negative literal.
+            Node::UnaryExpr {
+                op: Operator::Minus,
+                child,
+            } if lossless && child.is_literal() && !is_negative_literal(child) => {
+                write!(f, "-(")?;
+                child.write(f, lossless)?;
+                write!(f, ")")?;
+            }
+            _ => self.write(f, lossless)?,
         }
+        write!(f, ")")
+    }
+}
+
+pub struct Lossless<'a>(&'a Node, Position);
+
+impl fmt::Display for Lossless<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.write_at(f, self.1, true)
+    }
+}
+
+impl std::fmt::Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.write(f, false)
+    }
+}
+
+// Read-only traversal. `walk` calls the `visit_*` method of each node in
+// post-order, once its children have been visited, which is the order a stack
+// machine evaluates in. Returning an error stops the walk.
+//
+// `walk`, `walk_mut` and `fold` keep their own stack rather than recursing, as
+// a chain like `1 + 2 + ... + n` nests `n` deep. They match on `Node`
+// exhaustively, so adding a node kind forces all three traversals, and every
+// implementor relying on their defaults, to be updated together.
+pub trait Visitor {
+    fn visit_int(&mut self, _value: i32) -> Result<()> {
+        Ok(())
+    }
+
+    fn visit_float(&mut self, _value: f64) -> Result<()> {
+        Ok(())
+    }
+
+    fn visit_unary(&mut self, _op: Operator, _child: &Node) -> Result<()> {
+        Ok(())
+    }
+
+    fn visit_binary(&mut self, _op: Operator, _lhs: &Node, _rhs: &Node) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn walk<V: Visitor + ?Sized>(visitor: &mut V, node: &Node) -> Result<()> {
+    // Nodes to visit, each with whether its children have been pushed.
+    let mut stack = vec![(node, false)];
+    while let Some((node, expanded)) = stack.pop() {
+        match node {
+            Node::Int(value) => visitor.visit_int(*value)?,
+            Node::Float(value) => visitor.visit_float(*value)?,
+            Node::UnaryExpr { op, child } => {
+                if expanded {
+                    visitor.visit_unary(*op, child)?;
+                } else {
+                    stack.push((node, true));
+                    stack.push((child, false));
+                }
+            }
+            Node::BinaryExpr { op, lhs, rhs } => {
+                if expanded {
+                    visitor.visit_binary(*op, lhs, rhs)?;
+                } else {
+                    stack.push((node, true));
+                    stack.push((rhs, false));
+                    stack.push((lhs, false));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// In-place traversal, for rewrites that keep the shape of the tree. `walk_mut`
+// calls the `visit_*_mut` method of each node in pre-order, before its
+// children, which it then descends into as the method left them.
+pub trait VisitorMut {
+    fn visit_int_mut(&mut self, _value: &mut i32) {}
+
+    fn visit_float_mut(&mut self, _value: &mut f64) {}
+
+    fn visit_unary_mut(&mut self, _op: &mut Operator, _child: &mut Node) {}
+
+    fn visit_binary_mut(&mut self, _op: &mut Operator, _lhs: &mut Node, _rhs: &mut Node) {}
+}
+
+pub fn walk_mut<V: VisitorMut + ?Sized>(visitor: &mut V, node: &mut Node) {
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        match node {
+            Node::Int(value) => visitor.visit_int_mut(value),
+            Node::Float(value) => visitor.visit_float_mut(value),
+            Node::UnaryExpr { op, child } => {
+                visitor.visit_unary_mut(op, child);
+                stack.push(child);
+            }
+            Node::BinaryExpr { op, lhs, rhs } => {
+                visitor.visit_binary_mut(op, lhs, rhs);
+                stack.push(rhs);
+                stack.push(lhs);
+            }
+        }
+    }
+}
+
+// Owning, bottom-up rewrite that may replace any node with a different kind,
+// e.g. collapsing a subtree into a literal. `fold` calls the `fold_*` method
+// of each node with its children already folded.
+pub trait Fold {
+    fn fold_int(&mut self, value: i32) -> Node {
+        Node::Int(value)
+    }
+
+    fn fold_float(&mut self, value: f64) -> Node {
+        Node::Float(value)
+    }
+
+    fn fold_unary(&mut self, op: Operator, child: Node) -> Node {
+        Node::UnaryExpr {
+            op,
+            child: Box::new(child),
+        }
+    }
+
+    fn fold_binary(&mut self, op: Operator, lhs: Node, rhs: Node) -> Node {
+        Node::BinaryExpr {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        }
+    }
+}
+
+pub fn fold<F: Fold + ?Sized>(folder: &mut F, node: Node) -> Node {
+    enum Step {
+        Fold(Node),
+        // Operators whose operands are being folded, on top of `folded`.
+        Unary(Operator),
+        Binary(Operator),
+    }
+    let mut steps = vec![Step::Fold(node)];
+    let mut folded = Vec::new();
+    while let Some(step) = steps.pop() {
+        match step {
+            Step::Fold(mut node) => match &mut node {
+                Node::Int(value) => folded.push(folder.fold_int(*value)),
+                Node::Float(value) => folded.push(folder.fold_float(*value)),
+                Node::UnaryExpr { op, child } => {
+                    steps.push(Step::Unary(*op));
+                    steps.push(Step::Fold(Node::take(child)));
+                }
+                Node::BinaryExpr { op, lhs, rhs } => {
+                    steps.push(Step::Binary(*op));
+                    steps.push(Step::Fold(Node::take(rhs)));
+                    steps.push(Step::Fold(Node::take(lhs)));
+                }
+            },
+            Step::Unary(op) => {
+                let child = folded.pop().expect("operand was folded");
+                folded.push(folder.fold_unary(op, child));
+            }
+            Step::Binary(op) => {
+                let rhs = folded.pop().expect("operand was folded");
+                let lhs = folded.pop().expect("operand was folded");
+                folded.push(folder.fold_binary(op, lhs, rhs));
+            }
+        }
+    }
+    folded.pop().expect("node was folded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::testing::{identical, node};
+    use proptest::prelude::*;
+
+    fn reprint(source: &str) -> String {
+        parse(source).unwrap()[0].to_string()
+    }
+
+    #[test]
+    fn test_display_parentheses() {
+        // Operators share one precedence level and associate to the left,
+        // so these parentheses are redundant.
+        assert_eq!(reprint("(1 + 2) * 3"), "1 + 2 * 3");
+        assert_eq!(reprint("1 + (2 * 3)"), "1 + (2 * 3)");
+        assert_eq!(reprint("((1 + 2)) - (((3)))"), "1 + 2 - 3");
+        assert_eq!(reprint("-(1 + 2) * 3"), "-(1 + 2) * 3");
+        assert_eq!(reprint("1 + (-2)"), "1 + (-2)");
+        assert_eq!(reprint("-((-2))"), "-(-2)");
+    }
+
+    #[test]
+    fn test_display_reparses() {
+        for source in [
+            "1 + (2 - (3 * (4 / 5)))",
+            "-(1 - 2) / (-3)",
+            "(((1 + 2) * 3) - 4)",
+        ] {
+            let ast = parse(source).unwrap();
+            assert_eq!(parse(&ast[0].to_string()).unwrap(), ast);
+        }
+    }
+
+    fn round_trip(node: &Node) -> Node {
+        let printed = node.lossless().to_string();
+        let mut nodes = parse(&printed).unwrap_or_else(|err| panic!("{:?}: {}", printed, err));
+        assert_eq!(nodes.len(), 1);
+        nodes.remove(0)
+    }
+
+    #[test]
+    fn test_lossless_literals() {
+        let unary = |op, child| Node::UnaryExpr {
+            op,
+            child: Box::new(child),
+        };
+        let cases = [
+            (Node::Float(5.0), "5.0"),
+            (Node::Float(-0.0), "(-0.0)"),
+            (Node::Float(0.1), "0.1"),
+            (Node::Int(-5), "(-5)"),
+            (Node::Int(i32::MIN), "(-2147483648)"),
+            (unary(Operator::Minus, Node::Int(-1)), "-(-1)"),
+            (
+                unary(Operator::Minus, unary(Operator::Minus, Node::Int(1))),
+                "-(-(1))",
+            ),
+            (
+                Node::BinaryExpr {
+                    op: Operator::Minus,
+                    lhs: Box::new(Node::Int(-1)),
+                    rhs: Box::new(Node::Float(-2.5)),
+                },
+                "(-1) - (-2.5)",
+            ),
+        ];
+        for (node, expected) in cases {
+            assert_eq!(node.lossless().to_string(), expected);
+            assert!(identical(&round_trip(&node), &node), "{}", expected);
+        }
+        assert_eq!(Node::Float(5.0).to_string(), "5");
+        assert_eq!(Node::Int(-5).to_string(), "-5");
+        // NaN has no literal.
+        assert_eq!(Node::Float(f64::NAN).lossless().to_string(), "(0.0 / 0.0)");
+    }
+
+    #[test]
+    fn test_lossless_extreme_floats() {
+        for f in [
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            5e-324,
+            1e300,
+            1.0 / 3.0,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ] {
+            assert!(identical(&round_trip(&Node::Float(f)), &Node::Float(f)));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_lossless_round_trip(node in node()) {
+            let reparsed = round_trip(&node);
+            prop_assert!(identical(&reparsed, &node), "{:?} != {:?}", reparsed, node);
+        }
+    }
+
+    #[derive(Default)]
+    struct Counter {
+        literals: usize,
+        operators: Vec<Operator>,
+    }
+
+    impl Visitor for Counter {
+        fn visit_int(&mut self, _value: i32) -> Result<()> {
+            self.literals += 1;
+            Ok(())
+        }
+
+        fn visit_float(&mut self, _value: f64) -> Result<()> {
+            self.literals += 1;
+            Ok(())
+        }
+
+        fn visit_binary(&mut self, op: Operator, _lhs: &Node, _rhs: &Node) -> Result<()> {
+            self.operators.push(op);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_visitor() {
+        let ast = parse("-(1 + 2.5) * (3 / 4)").unwrap();
+        let mut counter = Counter::default();
+        walk(&mut counter, &ast[0]).unwrap();
+        assert_eq!(counter.literals, 4);
+        // Post-order, as a stack machine would emit them.
+        assert_eq!(
+            counter.operators,
+            vec![Operator::Plus, Operator::Divide, Operator::Multiply]
+        );
+    }
+
+    struct SwapAddSub;
+
+    impl VisitorMut for SwapAddSub {
+        fn visit_binary_mut(&mut self, op: &mut Operator, lhs: &mut Node, rhs: &mut Node) {
+            *op = match *op {
+                Operator::Plus => Operator::Minus,
+                Operator::Minus => Operator::Plus,
+                other => other,
+            };
+        }
+
+        fn visit_int_mut(&mut self, value: &mut i32) {
+            *value *= 10;
+        }
+    }
+
+    #[test]
+    fn test_visitor_mut() {
+        let mut ast = parse("1 + (2 - 3) * 4").unwrap();
+        walk_mut(&mut SwapAddSub, &mut ast[0]);
+        assert_eq!(ast, parse("10 - (20 + 30) * 40").unwrap());
+    }
+
+    // Replaces every unary plus by its operand.
+    struct DropUnaryPlus;
+
+    impl Fold for DropUnaryPlus {
+        fn fold_unary(&mut self, op: Operator, child: Node) -> Node {
+            match op {
+                Operator::Plus => child,
+                _ => Node::UnaryExpr {
+                    op,
+                    child: Box::new(child),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        let ast = parse("+(+1 - (-(+2)))").unwrap();
+        let folded = fold(&mut DropUnaryPlus, ast[0].clone());
+        assert_eq!(vec![folded], parse("1 - (-(2))").unwrap());
+    }
+
+    #[test]
+    fn test_long_chain() {
+        // Nested far deeper than recursing on it would allow.
+        let terms = 200_000;
+        let chain = (1..terms).fold(Node::Int(0), |lhs, i| Node::BinaryExpr {
+            op: Operator::Plus,
+            lhs: Box::new(lhs),
+            rhs: Box::new(Node::Int(i)),
+        });
+        assert_eq!(chain.size(), 2 * terms as usize - 1);
+        assert!(chain.clone() == chain);
+        let mut counter = Counter::default();
+        walk(&mut counter, &chain).unwrap();
+        assert_eq!(counter.literals, terms as usize);
+        let mut chain = fold(&mut DropUnaryPlus, chain);
+        walk_mut(&mut SwapAddSub, &mut chain);
+        assert!(chain.to_string().starts_with("0 - 10 - 20 - "));
     }
 }
@@ -22,7 +22,10 @@ fn main() -> Result<()> {
                 if line.is_empty() {
                     continue;
                 }
-                println!("{:?}", Engine::from_source(&line));
+                match Engine::from_source(&line).and_then(|result| result) {
+                    Ok(value) => println!("{:?}", value),
+                    Err(err) => eprintln!("error: {:#}", err),
+                }
             }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
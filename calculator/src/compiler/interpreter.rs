@@ -1,83 +1,218 @@
 use crate::Compile;
-use crate::ast::{Node, Operator};
+use crate::ast::{Node, Operator, Visitor, walk};
+use crate::compiler::limits::{Budget, Limits};
+use crate::compiler::runtime::RuntimeError;
 use crate::primitive::PrimitiveType;
+use anyhow::{Result, bail};
 
-struct Eval;
+// Evaluates in post-order like the VMs, with operands on a stack.
+struct Eval {
+    // Each node evaluated costs one unit of fuel.
+    budget: Budget,
+    stack: Vec<PrimitiveType>,
+    // Whether `budget` can run out at all.
+    metered: bool,
+}
 
 impl Eval {
-    pub fn new() -> Self {
-        Self
-    }
-
-    pub fn eval(&self, expr: &Node) -> PrimitiveType {
-        match expr {
-            Node::Int(n) => (*n).into(),
-            Node::Float(f) => (*f).into(),
-            Node::UnaryExpr { op, child } => {
-                let val = self.eval(child);
-                match op {
-                    Operator::Plus => val,
-                    Operator::Minus => -val,
-                    _ => unreachable!(),
-                }
-            }
-            Node::BinaryExpr { op, lhs, rhs } => {
-                let left = self.eval(lhs);
-                let right = self.eval(rhs);
-                match op {
-                    Operator::Plus => left + right,
-                    Operator::Minus => left - right,
-                    Operator::Multiply => left * right,
-                    Operator::Divide => left / right,
-                }
-            }
+    pub fn new(limits: Limits) -> Self {
+        let budget = Budget::new(limits);
+        Self {
+            metered: !budget.is_unlimited(),
+            budget,
+            stack: Vec::new(),
+        }
+    }
+
+    pub fn eval(&mut self, expr: &Node) -> Result<PrimitiveType> {
+        walk(self, expr)?;
+        Ok(self.pop())
+    }
+
+    fn charge(&mut self) -> Result<()> {
+        // Without limits there is nothing to charge for, like the VM's
+        // unmetered loop.
+        if self.metered {
+            self.budget.charge()?;
         }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> PrimitiveType {
+        self.stack.pop().expect("operand was evaluated")
+    }
+}
+
+impl Visitor for Eval {
+    fn visit_int(&mut self, value: i32) -> Result<()> {
+        self.charge()?;
+        self.stack.push(value.into());
+        Ok(())
+    }
+
+    fn visit_float(&mut self, value: f64) -> Result<()> {
+        self.charge()?;
+        self.stack.push(value.into());
+        Ok(())
+    }
+
+    fn visit_unary(&mut self, op: Operator, _child: &Node) -> Result<()> {
+        self.charge()?;
+        let val = self.pop();
+        match op {
+            Operator::Plus => self.stack.push(val),
+            Operator::Minus => self.stack.push(-val),
+            _ => bail!(RuntimeError::InvalidUnary(op)),
+        }
+        Ok(())
+    }
+
+    fn visit_binary(&mut self, op: Operator, _lhs: &Node, _rhs: &Node) -> Result<()> {
+        self.charge()?;
+        let right = self.pop();
+        let left = self.pop();
+        let value = left
+            .checked_binary(op, right)
+            .ok_or(RuntimeError::DivisionByZero)?;
+        self.stack.push(value);
+        Ok(())
     }
 }
 
 pub struct Interpreter;
 
+impl Interpreter {
+    // Like `from_ast`, but fails with `ResourceExhausted` once `limits` are
+    // reached. The tree-walker can't be resumed: fuel is per call.
+    //
+    // Like the VMs, returns the value of the last statement, and a program
+    // without statements is an error.
+    pub fn with_limits(ast: Vec<Node>, limits: Limits) -> Result<PrimitiveType> {
+        let mut eval = Eval::new(limits);
+        let mut out = None;
+        for node in ast {
+            out = Some(eval.eval(&node)?);
+        }
+        out.ok_or_else(|| RuntimeError::NoResult.into())
+    }
+}
+
 impl Compile for Interpreter {
-    type Output = PrimitiveType;
+    type Output = Result<PrimitiveType>;
 
     fn from_ast(ast: Vec<Node>) -> Self::Output {
-        let eval = Eval::new();
-        let mut out = PrimitiveType::Int(0);
-        for node in ast {
-            out = out + eval.eval(&node);
-        }
-        out
+        Self::with_limits(ast, Limits::default())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::compiler::limits::ResourceExhausted;
+    use crate::optimizer::OptLevel;
 
     #[test]
     fn test_interpreter() {
-        assert_eq!(Interpreter::from_source("21 + 6").unwrap(), 27.into());
-        assert_eq!(Interpreter::from_source("1 + 2 -3").unwrap(), 0.into());
+        assert_eq!(
+            Interpreter::from_source("21 + 6").unwrap().unwrap(),
+            27.into()
+        );
+        assert_eq!(
+            Interpreter::from_source("1 + 2 -3").unwrap().unwrap(),
+            0.into()
+        );
     }
 
     #[test]
     fn test_multiply_and_divide() {
-        assert_eq!(Interpreter::from_source("2 * 3").unwrap(), 6.into());
-        assert_eq!(Interpreter::from_source("8 / 2").unwrap(), 4.into());
+        assert_eq!(
+            Interpreter::from_source("2 * 3").unwrap().unwrap(),
+            6.into()
+        );
+        assert_eq!(
+            Interpreter::from_source("8 / 2").unwrap().unwrap(),
+            4.into()
+        );
     }
 
     #[test]
     fn test_operator_precedence() {
         // this should have been 8
-        assert_eq!(Interpreter::from_source("2 + 2 * 3").unwrap(), 12.into());
+        assert_eq!(
+            Interpreter::from_source("2 + 2 * 3").unwrap().unwrap(),
+            12.into()
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let ast = crate::parser::parse("1 + 2 * (-(3))").unwrap();
+        let limits = |fuel| Limits {
+            fuel: Some(fuel),
+            ..Limits::default()
+        };
+        // Two binary nodes, one unary node and three numbers.
+        assert_eq!(
+            Interpreter::with_limits(ast.clone(), limits(6)).unwrap(),
+            (-9).into()
+        );
+        let err = Interpreter::with_limits(ast, limits(5)).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ResourceExhausted::Fuel));
+    }
+
+    #[test]
+    fn test_long_chain() {
+        let source = format!("1{}", " + 1".repeat(200_000));
+        assert_eq!(
+            Interpreter::from_source(&source).unwrap().unwrap(),
+            200_001.into()
+        );
+        let err = Interpreter::from_source_with_depth("((1))", OptLevel::O0, 1).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&ResourceExhausted::Depth { max: 1 })
+        );
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let err = Interpreter::from_source("1 + 4 / (2 - 2)")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&RuntimeError::DivisionByZero));
+        assert_eq!(
+            Interpreter::from_source("1.0 / 0").unwrap().unwrap(),
+            f64::INFINITY.into()
+        );
+    }
+
+    #[test]
+    fn test_invalid_unary() {
+        let err = Interpreter::from_source("2 * (*5)").unwrap().unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&RuntimeError::InvalidUnary(Operator::Multiply))
+        );
+        assert_eq!(err.to_string(), "`*` is not a unary operator");
+    }
+
+    #[test]
+    fn test_empty_program() {
+        let err = Interpreter::from_ast(Vec::new()).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&RuntimeError::NoResult));
     }
 
     #[test]
     fn test_float_support() {
         assert_eq!(
-            Interpreter::from_source("2.5 + 2.5 + 1.5 + 2").unwrap(),
+            Interpreter::from_source("2.5 + 2.5 + 1.5 + 2")
+                .unwrap()
+                .unwrap(),
             8.5.into()
         );
-        assert_eq!(Interpreter::from_source("1.2 * 2").unwrap(), 2.4.into());
+        assert_eq!(
+            Interpreter::from_source("1.2 * 2").unwrap().unwrap(),
+            2.4.into()
+        );
     }
 }
@@ -1,5 +1,7 @@
 use crate::Compile;
-use crate::ast::{Node, Operator};
+use crate::ast::{Node, Operator, Visitor, walk};
+use crate::compiler::runtime::RuntimeError;
+use anyhow::{Result, bail};
 use inkwell::OptimizationLevel;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
@@ -9,50 +11,87 @@ use inkwell::values::{AnyValue, IntValue};
 
 type JitFunc = unsafe extern "C" fn() -> i32;
 
+// Builds in post-order like the VMs, with the operands' values on a stack.
 struct RecursiveBuilder<'a> {
     int_type: IntType<'a>,
     builder: &'a Builder<'a>,
+    stack: Vec<IntValue<'a>>,
 }
 
 impl<'a> RecursiveBuilder<'a> {
     pub fn new(int_type: IntType<'a>, builder: &'a Builder<'a>) -> Self {
-        Self { int_type, builder }
+        Self {
+            int_type,
+            builder,
+            stack: Vec::new(),
+        }
     }
 
-    pub fn build(&self, expr: &Node) -> IntValue<'a> {
-        match expr {
-            Node::Int(i) => self.int_type.const_int(*i as u64, true),
-            Node::UnaryExpr { op, child } => {
-                let val = self.build(child);
-                match op {
-                    Operator::Plus => val,
-                    Operator::Minus => val.const_neg(),
-                    _ => unreachable!(),
-                }
-            }
-            Node::BinaryExpr { op, lhs, rhs } => {
-                let left = self.build(lhs);
-                let right = self.build(rhs);
-                match op {
-                    Operator::Plus => self.builder.build_int_add(left, right, "add_temp").unwrap(),
-                    Operator::Minus => self.builder.build_int_sub(left, right, "sub_temp").unwrap(),
-                    Operator::Multiply => {
-                        self.builder.build_int_mul(left, right, "mul_temp").unwrap()
-                    }
-                    Operator::Divide => self
-                        .builder
-                        .build_int_signed_div(left, right, "div_temp")
-                        .unwrap(),
+    pub fn build(&mut self, expr: &Node) -> Result<IntValue<'a>> {
+        walk(self, expr)?;
+        Ok(self.pop())
+    }
+
+    fn pop(&mut self) -> IntValue<'a> {
+        self.stack.pop().expect("operand was built")
+    }
+}
+
+impl Visitor for RecursiveBuilder<'_> {
+    fn visit_int(&mut self, value: i32) -> Result<()> {
+        self.stack.push(self.int_type.const_int(value as u64, true));
+        Ok(())
+    }
+
+    fn visit_float(&mut self, _value: f64) -> Result<()> {
+        unimplemented!("the JIT only supports integers")
+    }
+
+    fn visit_unary(&mut self, op: Operator, _child: &Node) -> Result<()> {
+        let val = self.pop();
+        self.stack.push(match op {
+            Operator::Plus => val,
+            Operator::Minus => val.const_neg(),
+            _ => bail!(RuntimeError::InvalidUnary(op)),
+        });
+        Ok(())
+    }
+
+    fn visit_binary(&mut self, op: Operator, _lhs: &Node, _rhs: &Node) -> Result<()> {
+        let right = self.pop();
+        let left = self.pop();
+        let value = match op {
+            Operator::Plus => self.builder.build_int_add(left, right, "add_temp").unwrap(),
+            Operator::Minus => self.builder.build_int_sub(left, right, "sub_temp").unwrap(),
+            Operator::Multiply => self.builder.build_int_mul(left, right, "mul_temp").unwrap(),
+            // `sdiv` is undefined on a zero divisor and on `i32::MIN / -1`.
+            // Programs have no inputs, so the builder has folded every value
+            // to a constant, and the division can be done here instead, like
+            // the other engines: failing on zero and wrapping otherwise.
+            Operator::Divide => match (
+                left.get_sign_extended_constant(),
+                right.get_sign_extended_constant(),
+            ) {
+                (_, Some(0)) => bail!(RuntimeError::DivisionByZero),
+                (Some(lhs), Some(rhs)) => {
+                    let quotient = (lhs as i32).wrapping_div(rhs as i32);
+                    self.int_type.const_int(quotient as u64, true)
                 }
-            }
-        }
+                _ => self
+                    .builder
+                    .build_int_signed_div(left, right, "div_temp")
+                    .unwrap(),
+            },
+        };
+        self.stack.push(value);
+        Ok(())
     }
 }
 
 pub struct Jit;
 
 impl Compile for Jit {
-    type Output = i32;
+    type Output = Result<i32>;
     fn from_ast(ast: Vec<Node>) -> Self::Output {
         let context = Context::create();
         let module = context.create_module("calculator");
@@ -70,8 +109,8 @@ impl Compile for Jit {
         builder.position_at_end(basic_block);
 
         for node in ast {
-            let recursive_builder = RecursiveBuilder::new(i32_type, &builder);
-            let out_return = recursive_builder.build(&node);
+            let mut recursive_builder = RecursiveBuilder::new(i32_type, &builder);
+            let out_return = recursive_builder.build(&node)?;
             let _ = builder.build_return(Some(&out_return));
         }
 
@@ -82,7 +121,7 @@ impl Compile for Jit {
 
         unsafe {
             let jit_function: JitFunction<JitFunc> = execution_engine.get_function("jit").unwrap();
-            jit_function.call()
+            Ok(jit_function.call())
         }
     }
 }
@@ -93,19 +132,45 @@ mod tests {
 
     #[test]
     fn test_jit() {
-        assert_eq!(Jit::from_source("21 + 6").unwrap(), 27);
-        assert_eq!(Jit::from_source("1 + 2 -3").unwrap(), 0);
-        assert_eq!(Jit::from_source("1 + ((2 + 3) - (2 + 3))").unwrap(), 1);
+        assert_eq!(Jit::from_source("21 + 6").unwrap().unwrap(), 27);
+        assert_eq!(Jit::from_source("1 + 2 -3").unwrap().unwrap(), 0);
+        assert_eq!(
+            Jit::from_source("1 + ((2 + 3) - (2 + 3))")
+                .unwrap()
+                .unwrap(),
+            1
+        );
     }
 
     #[test]
     fn test_jit_multiply_and_divide() {
-        assert_eq!(Jit::from_source("2 * 3").unwrap(), 6);
-        assert_eq!(Jit::from_source("4 / 2").unwrap(), 2);
+        assert_eq!(Jit::from_source("2 * 3").unwrap().unwrap(), 6);
+        assert_eq!(Jit::from_source("4 / 2").unwrap().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let err = Jit::from_source("1 + 4 / (2 - 2)").unwrap().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&RuntimeError::DivisionByZero));
+        assert_eq!(
+            Jit::from_source("(-2147483647 - 1) / (-1)")
+                .unwrap()
+                .unwrap(),
+            i32::MIN
+        );
     }
 
     #[test]
     fn test_operator_precedence() {
-        assert_eq!(Jit::from_source("2 + 2 * 3").unwrap(), 12);
+        assert_eq!(Jit::from_source("2 + 2 * 3").unwrap().unwrap(), 12);
+    }
+
+    #[test]
+    fn test_invalid_unary() {
+        let err = Jit::from_source("*5").unwrap().unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&RuntimeError::InvalidUnary(Operator::Multiply))
+        );
     }
 }
@@ -0,0 +1,191 @@
+// Execution limits shared by the engines, for running untrusted programs:
+// a fuel budget of instructions (or nodes, for the tree-walking interpreter),
+// a wall-clock deadline, and a handle another thread can use to cancel.
+//
+// Running into any of them fails with `ResourceExhausted`. Fuel is spent
+// across runs, and a VM that ran out can be given more and resumed.
+//
+// Memory is capped where it is used, and running into those caps fails with
+// `ResourceExhausted` too: the parser's nesting depth, and the VM's stack
+// size (as `RuntimeError::StackOverflow`, see `runtime`) and constant pool.
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+// The deadline and cancellation are only looked at every this many steps,
+// since reading the clock costs far more than running an instruction.
+const CHECK_INTERVAL: u32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResourceExhausted {
+    Fuel,
+    Deadline,
+    Cancelled,
+    // Parentheses nested more than `max` deep.
+    Depth { max: usize },
+    Constants { max: usize },
+}
+
+impl fmt::Display for ResourceExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceExhausted::Fuel => write!(f, "resource exhausted: out of fuel"),
+            ResourceExhausted::Deadline => write!(f, "resource exhausted: deadline passed"),
+            ResourceExhausted::Cancelled => write!(f, "resource exhausted: cancelled"),
+            ResourceExhausted::Depth { max } => {
+                write!(f, "resource exhausted: nested more than {} deep", max)
+            }
+            ResourceExhausted::Constants { max } => {
+                write!(f, "resource exhausted: more than {} constants", max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResourceExhausted {}
+
+// Cancels a run from another thread. Clones share the flag, and once set it
+// stays set.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// No limits by default.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub fuel: Option<u64>,
+    pub deadline: Option<Instant>,
+    pub cancel: Option<CancelHandle>,
+}
+
+// `Limits` as they are used up by a run.
+#[derive(Debug, Clone)]
+pub struct Budget {
+    limits: Limits,
+    steps: u32,
+}
+
+impl Budget {
+    pub fn new(limits: Limits) -> Self {
+        Self { limits, steps: 0 }
+    }
+
+    // Whether `charge` can ever fail, so engines can skip it when not.
+    pub fn is_unlimited(&self) -> bool {
+        self.limits.fuel.is_none() && self.limits.deadline.is_none() && self.limits.cancel.is_none()
+    }
+
+    // Pays for one step, failing instead if the limits don't allow it.
+    #[inline]
+    pub fn charge(&mut self) -> Result<(), ResourceExhausted> {
+        if self.limits.fuel == Some(0) {
+            return Err(ResourceExhausted::Fuel);
+        }
+        if self.steps.is_multiple_of(CHECK_INTERVAL) {
+            self.check()?;
+        }
+        if let Some(fuel) = &mut self.limits.fuel {
+            *fuel -= 1;
+        }
+        self.steps = self.steps.wrapping_add(1);
+        Ok(())
+    }
+
+    fn check(&self) -> Result<(), ResourceExhausted> {
+        if let Some(cancel) = &self.limits.cancel
+            && cancel.is_cancelled()
+        {
+            return Err(ResourceExhausted::Cancelled);
+        }
+        if let Some(deadline) = self.limits.deadline
+            && Instant::now() >= deadline
+        {
+            return Err(ResourceExhausted::Deadline);
+        }
+        Ok(())
+    }
+
+    // The fuel left, if it is limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.limits.fuel
+    }
+
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(left) = &mut self.limits.fuel {
+            *left = left.saturating_add(fuel);
+        }
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.limits.deadline = deadline;
+        // Look at the new deadline on the next step.
+        self.steps = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_fuel() {
+        let mut budget = Budget::new(Limits {
+            fuel: Some(2),
+            ..Limits::default()
+        });
+        assert!(!budget.is_unlimited());
+        assert_eq!(budget.charge(), Ok(()));
+        assert_eq!(budget.charge(), Ok(()));
+        assert_eq!(budget.charge(), Err(ResourceExhausted::Fuel));
+        budget.add_fuel(1);
+        assert_eq!(budget.fuel(), Some(1));
+        assert_eq!(budget.charge(), Ok(()));
+
+        let mut unlimited = Budget::new(Limits::default());
+        assert!(unlimited.is_unlimited());
+        unlimited.add_fuel(1);
+        assert_eq!(unlimited.fuel(), None);
+        assert_eq!(unlimited.charge(), Ok(()));
+    }
+
+    #[test]
+    fn test_deadline_and_cancel() {
+        let past = Instant::now() - Duration::from_millis(1);
+        let mut budget = Budget::new(Limits {
+            deadline: Some(past),
+            ..Limits::default()
+        });
+        assert_eq!(budget.charge(), Err(ResourceExhausted::Deadline));
+        budget.set_deadline(None);
+        assert_eq!(budget.charge(), Ok(()));
+
+        let cancel = CancelHandle::new();
+        let mut budget = Budget::new(Limits {
+            cancel: Some(cancel.clone()),
+            ..Limits::default()
+        });
+        assert_eq!(budget.charge(), Ok(()));
+        thread::spawn(move || cancel.cancel()).join().unwrap();
+        // Cancellation is noticed at the next check, not the next step.
+        for _ in 1..CHECK_INTERVAL {
+            assert_eq!(budget.charge(), Ok(()));
+        }
+        assert_eq!(budget.charge(), Err(ResourceExhausted::Cancelled));
+    }
+}
@@ -3,6 +3,10 @@ pub mod interpreter;
 
 #[cfg(feature = "jit")]
 pub mod jit;
+pub mod limits;
+#[cfg(feature = "register-vm")]
+pub mod register;
+pub mod runtime;
 #[cfg(feature = "vm")]
 pub mod vm;
 // Interpreter — Executes source (or its AST) directly by evaluating it step-by-step at runtime.
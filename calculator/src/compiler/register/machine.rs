@@ -0,0 +1,148 @@
+use crate::Compile;
+use crate::ast::{Node, Operator};
+use crate::compiler::register::program::{Compiler, Instruction, Operand, Program};
+use crate::compiler::runtime::RuntimeError;
+use crate::primitive::PrimitiveType;
+use anyhow::Result;
+
+pub struct RegisterVM<'a> {
+    program: &'a Program,
+    registers: Vec<PrimitiveType>,
+}
+
+impl<'a> RegisterVM<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Self {
+            program,
+            registers: vec![PrimitiveType::Int(0); program.registers],
+        }
+    }
+
+    // Runs `program` to completion, returning the value of the last statement.
+    // Like the stack VM, a program without statements is an error.
+    pub fn execute(program: &Program) -> Result<PrimitiveType> {
+        let mut vm = RegisterVM::new(program);
+        vm.run()?;
+        vm.result().ok_or_else(|| RuntimeError::NoResult.into())
+    }
+
+    // Fails with `RuntimeError::DivisionByZero` on Int division by zero.
+    pub fn run(&mut self) -> Result<()> {
+        for instruction in &self.program.instructions {
+            let (dst, value) = match *instruction {
+                Instruction::Add(dst, lhs, rhs) => (dst, self.load(lhs) + self.load(rhs)),
+                Instruction::Sub(dst, lhs, rhs) => (dst, self.load(lhs) - self.load(rhs)),
+                Instruction::Mul(dst, lhs, rhs) => (dst, self.load(lhs) * self.load(rhs)),
+                Instruction::Div(dst, lhs, rhs) => {
+                    let quotient = self
+                        .load(lhs)
+                        .checked_binary(Operator::Divide, self.load(rhs))
+                        .ok_or(RuntimeError::DivisionByZero)?;
+                    (dst, quotient)
+                }
+                Instruction::Neg(dst, src) => (dst, -self.load(src)),
+            };
+            self.registers[usize::from(dst)] = value;
+        }
+        Ok(())
+    }
+
+    pub fn result(&self) -> Option<PrimitiveType> {
+        self.program.result.map(|result| self.load(result))
+    }
+
+    fn load(&self, operand: Operand) -> PrimitiveType {
+        match operand {
+            Operand::Register(r) => self.registers[usize::from(r)],
+            Operand::Constant(k) => self.program.constants[k as usize],
+        }
+    }
+}
+
+impl Compile for RegisterVM<'_> {
+    type Output = Result<PrimitiveType>;
+
+    fn from_ast(ast: Vec<Node>) -> Self::Output {
+        RegisterVM::execute(&Compiler::from_ast(ast)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, same_value};
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_register_vm() {
+        assert_eq!(
+            RegisterVM::from_source("1 + ((2 + 3) - (2 + 3))")
+                .unwrap()
+                .unwrap(),
+            1.into()
+        );
+        assert_eq!(
+            RegisterVM::from_source("1 + ((2 * 3) - (6 / 3))")
+                .unwrap()
+                .unwrap(),
+            5.into()
+        );
+        assert_eq!(
+            RegisterVM::from_source("1.2 + 3.6").unwrap().unwrap(),
+            4.8.into()
+        );
+        assert_eq!(
+            RegisterVM::from_source("-(1 - 4);").unwrap().unwrap(),
+            3.into()
+        );
+        let chain = format!("1{}", " + 1".repeat(200_000));
+        assert_eq!(
+            RegisterVM::from_source(&chain).unwrap().unwrap(),
+            200_001.into()
+        );
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let err = RegisterVM::from_source("1 + 4 / (2 - 2)")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&RuntimeError::DivisionByZero));
+        assert_eq!(
+            RegisterVM::from_source("1.0 / 0").unwrap().unwrap(),
+            f64::INFINITY.into()
+        );
+    }
+
+    #[test]
+    fn test_invalid_unary() {
+        let err = RegisterVM::from_source("*5").unwrap().unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&RuntimeError::InvalidUnary(Operator::Multiply))
+        );
+    }
+
+    #[test]
+    fn test_empty_program() {
+        let err = RegisterVM::execute(&Program::default()).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&RuntimeError::NoResult));
+    }
+
+    proptest! {
+        #[test]
+        fn test_matches_reference(node in testing::program()) {
+            let result = RegisterVM::from_ast(vec![node.clone()]);
+            match testing::eval(&node) {
+                Some(expected) => {
+                    let result = result.unwrap();
+                    prop_assert!(same_value(result, expected), "{:?} != {:?}", result, expected);
+                }
+                None => {
+                    let err = result.unwrap_err();
+                    prop_assert_eq!(err.downcast_ref(), Some(&RuntimeError::DivisionByZero));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,4 @@
+// Register-based alternative to the stack VM: three-address instructions
+// like `ADD r0, r0, k1`, whose operands are registers or constants.
+pub mod machine;
+pub mod program;
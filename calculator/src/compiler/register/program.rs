@@ -0,0 +1,206 @@
+use crate::Compile;
+use crate::ast::{Node, Operator, Visitor, walk};
+use crate::compiler::runtime::RuntimeError;
+use crate::primitive::{ConstantKey, PrimitiveType};
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Register(u16),
+    Constant(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Add(u16, Operand, Operand),
+    Sub(u16, Operand, Operand),
+    Mul(u16, Operand, Operand),
+    Div(u16, Operand, Operand),
+    Neg(u16, Operand),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub constants: Vec<PrimitiveType>,
+    // Number of registers the instructions use.
+    pub registers: usize,
+    // Where the value of the last statement ends up.
+    pub result: Option<Operand>,
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(r) => write!(f, "r{}", r),
+            Operand::Constant(k) => write!(f, "k{}", k),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::Add(dst, lhs, rhs) => write!(f, "ADD r{}, {}, {}", dst, lhs, rhs),
+            Instruction::Sub(dst, lhs, rhs) => write!(f, "SUB r{}, {}, {}", dst, lhs, rhs),
+            Instruction::Mul(dst, lhs, rhs) => write!(f, "MUL r{}, {}, {}", dst, lhs, rhs),
+            Instruction::Div(dst, lhs, rhs) => write!(f, "DIV r{}, {}, {}", dst, lhs, rhs),
+            Instruction::Neg(dst, src) => write!(f, "NEG r{}, {}", dst, src),
+        }
+    }
+}
+
+// One instruction per line, then the constants and the result.
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for instruction in &self.instructions {
+            writeln!(f, "{}", instruction)?;
+        }
+        for (k, constant) in self.constants.iter().enumerate() {
+            writeln!(f, "k{} = {:?}", k, constant)?;
+        }
+        match self.result {
+            Some(result) => writeln!(f, "result {}", result),
+            None => writeln!(f, "result none"),
+        }
+    }
+}
+
+// Lowers the AST to register code. Registers are allocated like a stack:
+// an operator frees its operands' registers before taking one for its
+// result, so `1 + 2 + 3` reuses `r0` throughout.
+#[derive(Default)]
+pub struct Compiler {
+    program: Program,
+    constant_indices: HashMap<ConstantKey, u32>,
+    next_register: u16,
+    // Where the values of the nodes compiled so far, but not yet used by
+    // their parent, end up.
+    operands: Vec<Operand>,
+}
+
+impl Compile for Compiler {
+    type Output = Result<Program>;
+
+    fn from_ast(ast: Vec<Node>) -> Self::Output {
+        let mut compiler = Compiler::default();
+        for node in ast {
+            compiler.next_register = 0;
+            walk(&mut compiler, &node)?;
+            compiler.program.result = Some(compiler.pop());
+        }
+        Ok(compiler.program)
+    }
+}
+
+impl Compiler {
+    fn add_constant(&mut self, value: PrimitiveType) -> Operand {
+        let constants = &mut self.program.constants;
+        let index = *self
+            .constant_indices
+            .entry(value.into())
+            .or_insert_with(|| {
+                constants.push(value);
+                u32::try_from(constants.len() - 1).expect("too many constants")
+            });
+        Operand::Constant(index)
+    }
+
+    fn free(&mut self, operand: Operand) {
+        if let Operand::Register(r) = operand {
+            self.next_register = r;
+        }
+    }
+
+    fn allocate(&mut self) -> u16 {
+        let register = self.next_register;
+        self.next_register += 1;
+        self.program.registers = self.program.registers.max(usize::from(self.next_register));
+        register
+    }
+
+    fn pop(&mut self) -> Operand {
+        self.operands.pop().expect("operand was compiled")
+    }
+}
+
+// Each node leaves where its value ends up on `operands`, in post-order.
+impl Visitor for Compiler {
+    fn visit_int(&mut self, value: i32) -> Result<()> {
+        let operand = self.add_constant(PrimitiveType::Int(value));
+        self.operands.push(operand);
+        Ok(())
+    }
+
+    fn visit_float(&mut self, value: f64) -> Result<()> {
+        let operand = self.add_constant(PrimitiveType::Float(value));
+        self.operands.push(operand);
+        Ok(())
+    }
+
+    fn visit_unary(&mut self, op: Operator, _child: &Node) -> Result<()> {
+        let src = self.pop();
+        let operand = match op {
+            Operator::Plus => src,
+            Operator::Minus => {
+                self.free(src);
+                let dst = self.allocate();
+                self.program.instructions.push(Instruction::Neg(dst, src));
+                Operand::Register(dst)
+            }
+            _ => bail!(RuntimeError::InvalidUnary(op)),
+        };
+        self.operands.push(operand);
+        Ok(())
+    }
+
+    fn visit_binary(&mut self, op: Operator, _lhs: &Node, _rhs: &Node) -> Result<()> {
+        let rhs = self.pop();
+        let lhs = self.pop();
+        self.free(rhs);
+        self.free(lhs);
+        let dst = self.allocate();
+        let instruction = match op {
+            Operator::Plus => Instruction::Add(dst, lhs, rhs),
+            Operator::Minus => Instruction::Sub(dst, lhs, rhs),
+            Operator::Multiply => Instruction::Mul(dst, lhs, rhs),
+            Operator::Divide => Instruction::Div(dst, lhs, rhs),
+        };
+        self.program.instructions.push(instruction);
+        self.operands.push(Operand::Register(dst));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile() {
+        let program = Compiler::from_source("1 + 2 * (3 - (-(1)))")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            program.to_string(),
+            "ADD r0, k0, k1\n\
+             NEG r1, k0\n\
+             SUB r1, k2, r1\n\
+             MUL r0, r0, r1\n\
+             k0 = Int(1)\n\
+             k1 = Int(2)\n\
+             k2 = Int(3)\n\
+             result r0\n"
+        );
+        assert_eq!(program.registers, 2);
+    }
+
+    #[test]
+    fn test_compile_literal() {
+        let program = Compiler::from_source("+2.5").unwrap().unwrap();
+        assert!(program.instructions.is_empty());
+        assert_eq!(program.result, Some(Operand::Constant(0)));
+    }
+}
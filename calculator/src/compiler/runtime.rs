@@ -0,0 +1,35 @@
+use crate::ast::Operator;
+use std::fmt;
+
+// Errors raised while running, as opposed to ones found by the VM's verifier,
+// shared by the engines. They reach callers inside `anyhow::Error` and can be
+// downcast. In the stack VM, errors an instruction fails with have its place
+// in the source as context, e.g. "at 1:5: division by zero".
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    StackOverflow { size: usize },
+    StackUnderflow,
+    // Int division by zero; Float division gives an infinity or NaN.
+    DivisionByZero,
+    // The program has no statements, so there is no value to return.
+    NoResult,
+    // `*` or `/` used as a unary operator, which the parser accepts. The
+    // compiling engines report it before running anything.
+    InvalidUnary(Operator),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::StackOverflow { size } => {
+                write!(f, "stack overflow: more than {} values", size)
+            }
+            RuntimeError::StackUnderflow => write!(f, "stack underflow"),
+            RuntimeError::DivisionByZero => write!(f, "division by zero"),
+            RuntimeError::NoResult => write!(f, "the program has no result"),
+            RuntimeError::InvalidUnary(op) => write!(f, "`{}` is not a unary operator", op),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
@@ -1,15 +1,27 @@
 use crate::Compile;
-use crate::ast::Node;
 use crate::ast::Operator;
+use crate::ast::{Node, Span};
+use crate::ast::{Visitor, walk};
+use crate::compiler::runtime::RuntimeError;
 use crate::compiler::vm::opcode::{OpCode, make_op};
-use crate::primitive::PrimitiveType;
-use anyhow::Result;
+use crate::compiler::vm::peephole;
+use crate::compiler::vm::source_map::{Run, SourceMap};
+use crate::dump::{float_atom, literal_from_atom};
+use crate::optimizer::{self, OptLevel};
+use crate::parser;
+use crate::primitive::{ConstantKey, PrimitiveType};
+use crate::sexpr::{self, Sexpr};
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::str::RSplit;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct Bytecode {
     pub instructions: Vec<u8>,
     pub constants: Vec<PrimitiveType>,
+    // Empty unless compiled from source, see `Interpreter::from_source_mapped`.
+    pub source_map: SourceMap,
 }
 
 impl Bytecode {
@@ -17,26 +29,198 @@ impl Bytecode {
         Self {
             instructions: Vec::new(),
             constants: Vec::new(),
+            source_map: SourceMap::new(),
         }
     }
+
+    pub fn from_ops(ops: &[OpCode], constants: Vec<PrimitiveType>) -> Bytecode {
+        Self {
+            instructions: ops.iter().copied().flat_map(make_op).collect(),
+            constants,
+            source_map: SourceMap::new(),
+        }
+    }
+
+    pub fn decode(&self) -> Result<Vec<OpCode>> {
+        // Most instructions are a single byte.
+        let mut ops = Vec::with_capacity(self.instructions.len());
+        let mut offset = 0;
+        while offset < self.instructions.len() {
+            let (op, len) = OpCode::decode(&self.instructions[offset..])
+                .with_context(|| format!("at offset {}", offset))?;
+            ops.push(op);
+            offset += len;
+        }
+        Ok(ops)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        let listing = Listing {
+            constants: self.constants.clone(),
+            instructions: self.decode()?,
+            source_map: self.source_map.clone(),
+        };
+        Ok(serde_json::to_string_pretty(&listing)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Bytecode> {
+        let listing: Listing = serde_json::from_str(json)?;
+        Ok(Bytecode {
+            source_map: listing.source_map,
+            ..Bytecode::from_ops(&listing.instructions, listing.constants)
+        })
+    }
+
+    // (bytecode (constants (int 1) (float 2.5)) (instructions (OP_CONSTANT 0) OP_POP)),
+    // with a (spans (offset line column start end) ...) section after the
+    // constants if it has a source map.
+    pub fn to_sexpr(&self) -> Result<String> {
+        let constants = self.constants.iter().map(|constant| {
+            let (tag, value) = match constant {
+                PrimitiveType::Int(n) => ("int", n.to_string()),
+                PrimitiveType::Float(f) => ("float", float_atom(*f)),
+            };
+            Sexpr::List(vec![Sexpr::atom(tag), Sexpr::atom(value)])
+        });
+        let instructions = self.decode()?.into_iter().map(|op| match op {
+            OpCode::Constant(index) => {
+                Sexpr::List(vec![Sexpr::atom(op.name()), Sexpr::atom(index.to_string())])
+            }
+            OpCode::ConstantLong(index) => {
+                Sexpr::List(vec![Sexpr::atom(op.name()), Sexpr::atom(index.to_string())])
+            }
+            _ => Sexpr::atom(op.name()),
+        });
+        let section = |name: &str, items: Vec<Sexpr>| {
+            Sexpr::List(std::iter::once(Sexpr::atom(name)).chain(items).collect())
+        };
+        let mut sections = vec![
+            Sexpr::atom("bytecode"),
+            section("constants", constants.collect()),
+        ];
+        if !self.source_map.is_empty() {
+            let runs = self.source_map.runs().iter().map(|run| {
+                let Span {
+                    start,
+                    end,
+                    line,
+                    column,
+                } = run.span;
+                let fields = [run.offset, line, column, start, end];
+                Sexpr::List(fields.map(|n| Sexpr::atom(n.to_string())).to_vec())
+            });
+            sections.push(section("spans", runs.collect()));
+        }
+        sections.push(section("instructions", instructions.collect()));
+        let bytecode = Sexpr::List(sections);
+        Ok(format!("{}\n", bytecode))
+    }
+
+    pub fn from_sexpr(source: &str) -> Result<Bytecode> {
+        let parsed = sexpr::parse_all(source)?;
+        let [Sexpr::List(items)] = parsed.as_slice() else {
+            bail!("expected a single (bytecode ...) expression");
+        };
+        let [head, constants, optional @ .., instructions] = items.as_slice() else {
+            bail!("expected (bytecode (constants ...) (instructions ...))");
+        };
+        if head.as_atom()? != "bytecode" {
+            bail!("expected (bytecode ...), found ({} ...)", head);
+        }
+
+        let constants = section_items(constants, "constants")?
+            .iter()
+            .map(|constant| {
+                let [tag, value] = constant.as_list()? else {
+                    bail!("expected (int n) or (float f), found {}", constant);
+                };
+                let literal = literal_from_atom(value.as_atom()?)?;
+                match (tag.as_atom()?, literal) {
+                    ("int", Node::Int(n)) => Ok(PrimitiveType::Int(n)),
+                    ("float", Node::Float(f)) => Ok(PrimitiveType::Float(f)),
+                    _ => bail!("invalid constant {}", constant),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut source_map = SourceMap::new();
+        for section in optional {
+            let runs = section_items(section, "spans")?
+                .iter()
+                .map(|run| {
+                    let [offset, line, column, start, end] = run
+                        .as_list()?
+                        .iter()
+                        .map(|field| Ok(field.as_atom()?.parse()?))
+                        .collect::<Result<Vec<usize>>>()?[..]
+                    else {
+                        bail!("expected (offset line column start end), found {}", run);
+                    };
+                    let span = Span {
+                        start,
+                        end,
+                        line,
+                        column,
+                    };
+                    Ok(Run { offset, span })
+                })
+                .collect::<Result<_>>()?;
+            source_map = SourceMap::from_runs(runs);
+        }
+        let ops = section_items(instructions, "instructions")?
+            .iter()
+            .map(|instruction| match instruction {
+                Sexpr::Atom(name) => OpCode::from_name(name, None),
+                Sexpr::List(items) => match items.as_slice() {
+                    [name, operand] => {
+                        OpCode::from_name(name.as_atom()?, Some(operand.as_atom()?.parse()?))
+                    }
+                    _ => bail!("invalid instruction {}", instruction),
+                },
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Bytecode {
+            source_map,
+            ..Bytecode::from_ops(&ops, constants)
+        })
+    }
+}
+
+// Bytecode with its instructions decoded, as exchanged with other tools.
+#[derive(Serialize, Deserialize)]
+struct Listing {
+    constants: Vec<PrimitiveType>,
+    instructions: Vec<OpCode>,
+    #[serde(default, skip_serializing_if = "SourceMap::is_empty")]
+    source_map: SourceMap,
+}
+
+fn section_items<'a>(section: &'a Sexpr, name: &str) -> Result<&'a [Sexpr]> {
+    match section.as_list()?.split_first() {
+        Some((head, items)) if head.as_atom()? == name => Ok(items),
+        _ => bail!("expected ({} ...), found {}", name, section),
+    }
 }
 
+// Constants are deduplicated by their bits, see `ConstantKey`.
+#[derive(Default)]
 pub struct Interpreter {
     pub bytecode: Bytecode,
+    constant_indices: HashMap<ConstantKey, u32>,
+    // The spans of the nodes still to compile, in post-order, and of the one
+    // being compiled, which its instructions are mapped to.
+    spans: std::vec::IntoIter<Span>,
+    span: Option<Span>,
 }
 
 impl Compile for Interpreter {
     type Output = Result<Bytecode>;
 
     fn from_ast(ast: Vec<Node>) -> Self::Output {
-        let mut interpreter = Interpreter::new();
-        for node in ast {
-            interpreter.interpret_node(node);
-            // pop one element from stack after each expression
-            // statement to clean up.
-            interpreter.add_instruction(OpCode::OpPop);
-        }
-        Ok(interpreter.bytecode)
+        Self::from_spanned_ast(ast, Vec::new(), OptLevel::O0)
+    }
+
+    fn from_ast_with(ast: Vec<Node>, level: OptLevel) -> Self::Output {
+        Self::from_spanned_ast(ast, Vec::new(), level)
     }
 }
 
@@ -44,47 +228,141 @@ impl Interpreter {
     pub fn new() -> Self {
         Self {
             bytecode: Bytecode::new(),
+            constant_indices: HashMap::new(),
+            spans: Vec::new().into_iter(),
+            span: None,
+        }
+    }
+
+    // Compiles `source` with a source map.
+    pub fn from_source_mapped(source: &str, level: OptLevel) -> Result<Bytecode> {
+        Self::from_source_mapped_with_depth(source, level, parser::MAX_DEPTH)
+    }
+
+    // Like `from_source_mapped`, but fails with `ResourceExhausted::Depth` if
+    // parentheses are nested more than `max_depth` deep.
+    pub fn from_source_mapped_with_depth(
+        source: &str,
+        level: OptLevel,
+        max_depth: usize,
+    ) -> Result<Bytecode> {
+        let (ast, spans) = parser::parse_spanned_with(source, max_depth)?;
+        Self::from_spanned_ast(ast, spans, level)
+    }
+
+    // Like `from_ast_with`, mapping the instructions of each node to its span
+    // in `spans`, which are in post-order as from `parser::parse_spanned`.
+    // Without spans the source map is left empty.
+    //
+    // The optimizer doesn't keep track of spans, so once it has run, every
+    // instruction of a statement is mapped to the whole statement.
+    pub fn from_spanned_ast(ast: Vec<Node>, spans: Vec<Span>, level: OptLevel) -> Result<Bytecode> {
+        if level == OptLevel::O0 {
+            return Self::compile(ast, spans);
+        }
+        // The span of each statement's root, which is the last of its nodes.
+        let mut end = 0;
+        let statements: Vec<Span> = ast
+            .iter()
+            .map_while(|node| {
+                end += node.size();
+                spans.get(end - 1).copied()
+            })
+            .collect();
+        let ast = optimizer::optimize(ast, level);
+        let spans = ast
+            .iter()
+            .zip(statements)
+            .flat_map(|(node, span)| std::iter::repeat_n(span, node.size()))
+            .collect();
+        peephole::optimize(&Self::compile(ast, spans)?)
+    }
+
+    fn compile(ast: Vec<Node>, spans: Vec<Span>) -> Result<Bytecode> {
+        let mut interpreter = Interpreter {
+            spans: spans.into_iter(),
+            ..Interpreter::new()
+        };
+        let last = ast.len().saturating_sub(1);
+        for (i, node) in ast.into_iter().enumerate() {
+            interpreter.interpret_node(node)?;
+            // Every statement leaves its value on the stack: the last one
+            // returns it, the others pop it to clean up. Both are mapped to
+            // the statement, whose node was compiled last.
+            let end = if i == last {
+                OpCode::Return
+            } else {
+                OpCode::Pop
+            };
+            interpreter.add_instruction(end);
         }
+        Ok(interpreter.bytecode)
     }
 
     pub fn add_instruction(&mut self, opcode: OpCode) {
+        if let Some(span) = self.span {
+            let offset = self.bytecode.instructions.len();
+            self.bytecode.source_map.push(offset, span);
+        }
         self.bytecode.instructions.extend(make_op(opcode));
     }
 
-    pub fn add_constant(&mut self, node: PrimitiveType) -> u16 {
-        self.bytecode.constants.push(node);
-        self.bytecode.constants.len() as u16 - 1
+    // Moves on to the span of the next node, once its children are compiled.
+    fn next_span(&mut self) {
+        self.span = self.spans.next();
     }
 
-    pub fn interpret_node(&mut self, expr: Node) {
-        match expr {
-            Node::Int(d) => {
-                let const_index = self.add_constant(PrimitiveType::Int(d));
-                self.add_instruction(OpCode::OpConstant(const_index));
-            }
-            Node::Float(d) => {
-                let const_index = self.add_constant(PrimitiveType::Float(d));
-                self.add_instruction(OpCode::OpConstant(const_index));
-            }
-            Node::UnaryExpr { op, child } => {
-                self.interpret_node(*child);
-                match op {
-                    Operator::Plus => self.add_instruction(OpCode::OpPlus),
-                    Operator::Minus => self.add_instruction(OpCode::OpMinus),
-                    _ => unreachable!(),
-                }
-            }
-            Node::BinaryExpr { op, lhs, rhs } => {
-                self.interpret_node(*lhs);
-                self.interpret_node(*rhs);
-                match op {
-                    Operator::Plus => self.add_instruction(OpCode::OpAdd),
-                    Operator::Minus => self.add_instruction(OpCode::OpSub),
-                    Operator::Multiply => self.add_instruction(OpCode::OpMul),
-                    Operator::Divide => self.add_instruction(OpCode::OpDiv),
-                }
-            }
+    pub fn add_constant(&mut self, node: PrimitiveType) -> u32 {
+        let constants = &mut self.bytecode.constants;
+        *self.constant_indices.entry(node.into()).or_insert_with(|| {
+            constants.push(node);
+            u32::try_from(constants.len() - 1).expect("too many constants")
+        })
+    }
+
+    pub fn add_constant_instruction(&mut self, node: PrimitiveType) {
+        let const_index = self.add_constant(node);
+        self.add_instruction(OpCode::constant(const_index));
+    }
+
+    pub fn interpret_node(&mut self, expr: Node) -> Result<()> {
+        walk(self, &expr)
+    }
+}
+
+// Operands are emitted before their operator, i.e. in post-order.
+impl Visitor for Interpreter {
+    fn visit_int(&mut self, value: i32) -> Result<()> {
+        self.next_span();
+        self.add_constant_instruction(PrimitiveType::Int(value));
+        Ok(())
+    }
+
+    fn visit_float(&mut self, value: f64) -> Result<()> {
+        self.next_span();
+        self.add_constant_instruction(PrimitiveType::Float(value));
+        Ok(())
+    }
+
+    fn visit_unary(&mut self, op: Operator, _child: &Node) -> Result<()> {
+        self.next_span();
+        match op {
+            Operator::Plus => self.add_instruction(OpCode::Plus),
+            Operator::Minus => self.add_instruction(OpCode::Minus),
+            _ => bail!(RuntimeError::InvalidUnary(op)),
         }
+        Ok(())
+    }
+
+    fn visit_binary(&mut self, op: Operator, _lhs: &Node, _rhs: &Node) -> Result<()> {
+        self.next_span();
+        match op {
+            Operator::Plus => self.add_instruction(OpCode::Add),
+            Operator::Minus => self.add_instruction(OpCode::Sub),
+            Operator::Multiply => self.add_instruction(OpCode::Mul),
+            Operator::Divide => self.add_instruction(OpCode::Div),
+        }
+        Ok(())
     }
 }
 
@@ -99,26 +377,94 @@ mod tests {
             let input = format!("1 {} 2", sign);
             let bytecode = Interpreter::from_source(&input).unwrap().unwrap();
             let op_code = match sign {
-                "+" => OpCode::OpAdd,
-                "-" => OpCode::OpSub,
+                "+" => OpCode::Add,
+                "-" => OpCode::Sub,
                 _ => unreachable!(),
             };
             let expected_instructions = vec![
-                OpCode::OpConstant(0),
-                OpCode::OpConstant(1),
+                OpCode::Constant(0),
+                OpCode::Constant(1),
                 op_code,
-                OpCode::OpPop,
+                OpCode::Return,
             ]
             .into_iter()
-            .flat_map(|op_code| make_op(op_code))
+            .flat_map(make_op)
             .collect();
             assert_eq!(
                 Bytecode {
                     instructions: expected_instructions,
-                    constants: vec![PrimitiveType::Int(1), PrimitiveType::Int(2)]
+                    constants: vec![PrimitiveType::Int(1), PrimitiveType::Int(2)],
+                    source_map: SourceMap::new(),
                 },
                 bytecode
             );
         }
     }
+
+    #[test]
+    fn test_constant_deduplication() {
+        let bytecode = Interpreter::from_source("1 + 1 + 1.0 + 1")
+            .unwrap()
+            .unwrap();
+        assert_eq!(bytecode.constants, vec![1.into(), 1.0.into()]);
+
+        let mut interpreter = Interpreter::new();
+        for value in [0.0, -0.0, f64::NAN, 0.0, f64::NAN] {
+            interpreter.add_constant_instruction(value.into());
+        }
+        let constants = interpreter.bytecode.constants;
+        assert_eq!(constants.len(), 3);
+        assert!(matches!(constants[1], PrimitiveType::Float(f) if f.is_sign_negative()));
+    }
+
+    #[test]
+    fn test_long_constant_index() {
+        let mut interpreter = Interpreter::new();
+        for value in 0..=65536 {
+            interpreter.add_constant_instruction(value.into());
+        }
+        let ops = interpreter.bytecode.decode().unwrap();
+        assert_eq!(ops[65535], OpCode::Constant(65535));
+        assert_eq!(ops[65536], OpCode::ConstantLong(65536));
+
+        let bytecode = Bytecode::from_ops(&ops[65535..], interpreter.bytecode.constants);
+        let sexpr = bytecode.to_sexpr().unwrap();
+        assert!(sexpr.ends_with("(instructions (OP_CONSTANT 65535) (OP_CONSTANT_LONG 65536)))\n"));
+        assert_eq!(Bytecode::from_sexpr(&sexpr).unwrap(), bytecode);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let bytecode = Interpreter::from_source("-1 + 2.5").unwrap().unwrap();
+        let json = bytecode.to_json().unwrap();
+        let compact: String = json.split_whitespace().collect();
+        assert_eq!(
+            compact,
+            r#"{"constants":[{"Int":1},{"Float":2.5}],"instructions":[{"OP_CONSTANT":0},"OP_MINUS",{"OP_CONSTANT":1},"OP_ADD","OP_RETURN"]}"#
+        );
+        assert_eq!(Bytecode::from_json(&json).unwrap(), bytecode);
+    }
+
+    #[test]
+    fn test_sexpr_round_trip() {
+        let bytecode = Interpreter::from_source("-1 + 2.0").unwrap().unwrap();
+        let sexpr = bytecode.to_sexpr().unwrap();
+        assert_eq!(
+            sexpr,
+            "(bytecode (constants (int 1) (float 2.0)) \
+             (instructions (OP_CONSTANT 0) OP_MINUS (OP_CONSTANT 1) OP_ADD OP_RETURN))\n"
+        );
+        assert_eq!(Bytecode::from_sexpr(&sexpr).unwrap(), bytecode);
+        assert!(Bytecode::from_sexpr("(bytecode (constants (int 1.5)) (instructions))").is_err());
+        assert!(Bytecode::from_sexpr("(bytecode (constants) (instructions (OP_ADD 1)))").is_err());
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let bytecode = Bytecode {
+            instructions: vec![0x02, 0x01, 0x00],
+            ..Bytecode::default()
+        };
+        assert_eq!(bytecode.decode().unwrap_err().to_string(), "at offset 1");
+    }
 }
@@ -0,0 +1,193 @@
+// The commands behind `calculator debug`, apart from the terminal so that a
+// session can be scripted and tested. Each command is one line:
+//
+//   step, s, next, n       run one instruction
+//   continue, c            run to the next breakpoint or the end
+//   break, b <line>        break wherever running enters a source line
+//   break, b *<offset>     break at the instruction at a byte offset
+//   delete, d <line>       and `delete *<offset>` remove breakpoints
+//   print, p [constants]   show the stack, or the constants
+//   backtrace, bt          show where the program is
+//   quit, q
+//
+// Without calls, stepping over an instruction is the same as stepping into
+// it, and the backtrace has one frame.
+use crate::compiler::vm::bytecode::Bytecode;
+use crate::compiler::vm::vm::VM;
+use anyhow::{Context, Result, bail};
+use std::collections::HashMap;
+use std::io::Write;
+
+pub struct Debugger {
+    vm: VM,
+    // The disassembly of each instruction, by offset.
+    listing: HashMap<usize, String>,
+}
+
+// Where a breakpoint command points.
+enum Location {
+    Line(usize),
+    Offset(usize),
+}
+
+impl Debugger {
+    pub fn new(bytecode: Bytecode) -> Result<Self> {
+        let listing = bytecode
+            .disassemble()?
+            .lines()
+            .filter_map(|line| {
+                let offset = line.split_whitespace().next()?.parse().ok()?;
+                Some((offset, line.to_string()))
+            })
+            .collect();
+        Ok(Self {
+            vm: VM::new(bytecode)?,
+            listing,
+        })
+    }
+
+    // Writes the next instruction, or the result once the program stopped.
+    pub fn show(&self, out: &mut impl Write) -> Result<()> {
+        match self.listing.get(&self.vm.ip()) {
+            Some(line) if !self.vm.is_halted() => writeln!(out, "{}", line)?,
+            _ => writeln!(out, "halted, result {:?}", self.vm.result())?,
+        }
+        Ok(())
+    }
+
+    // Runs one command, returning false if it ends the session. A command
+    // that fails, e.g. a step into a division by zero, leaves the session
+    // open.
+    pub fn command(&mut self, line: &str, out: &mut impl Write) -> Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["step" | "s" | "next" | "n"] => {
+                self.vm.step()?;
+                self.show(out)?;
+            }
+            ["continue" | "c"] => {
+                self.vm.run_to_breakpoint()?;
+                self.show(out)?;
+            }
+            ["break" | "b", location] => {
+                let offsets = match parse_location(location)? {
+                    Location::Line(line) => self.vm.add_line_breakpoint(line)?,
+                    Location::Offset(offset) => {
+                        self.vm.add_breakpoint(offset)?;
+                        vec![offset]
+                    }
+                };
+                for offset in offsets {
+                    writeln!(out, "breakpoint at {:04}", offset)?;
+                }
+            }
+            ["delete" | "d", location] => {
+                let removed = match parse_location(location)? {
+                    Location::Line(line) => self.vm.remove_line_breakpoint(line),
+                    Location::Offset(offset) => self.vm.remove_breakpoint(offset),
+                };
+                if !removed {
+                    bail!("no breakpoint at {}", location);
+                }
+            }
+            ["print" | "p"] => writeln!(out, "stack {:?}", self.vm.stack())?,
+            ["print" | "p", "constants"] => {
+                for (index, constant) in self.vm.constants().iter().enumerate() {
+                    writeln!(out, "{} = {:?}", index, constant)?;
+                }
+            }
+            ["backtrace" | "bt"] => {
+                write!(out, "#0 ")?;
+                self.show(out)?;
+            }
+            ["quit" | "q"] => return Ok(false),
+            _ => bail!("unknown command {:?}", line.trim()),
+        }
+        Ok(true)
+    }
+}
+
+// `3` is line 3 of the source, `*3` the byte offset 3, as in gdb.
+fn parse_location(location: &str) -> Result<Location> {
+    match location.strip_prefix('*') {
+        Some(offset) => Ok(Location::Offset(
+            offset.parse().context("expected an offset after *")?,
+        )),
+        None => Ok(Location::Line(
+            location.parse().context("expected a line or *<offset>")?,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::vm::bytecode::Interpreter;
+    use crate::optimizer::OptLevel;
+
+    // Runs `commands` one per line, returning what the session printed, with
+    // errors printed as the CLI does.
+    fn session(source: &str, commands: &str) -> String {
+        let bytecode = Interpreter::from_source_mapped(source, OptLevel::O0).unwrap();
+        let mut debugger = Debugger::new(bytecode).unwrap();
+        let mut out = Vec::new();
+        debugger.show(&mut out).unwrap();
+        for line in commands.lines() {
+            match debugger.command(line, &mut out) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(err) => writeln!(out, "error: {:#}", err).unwrap(),
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_step_and_print() {
+        let output = session("1 + 2", "s\np\nn\np constants\nbt\nc\nq\ns");
+        assert_eq!(
+            output,
+            "0000 OP_CONSTANT      0 (1)      ; 1:1\n\
+             0003 OP_CONSTANT      1 (2)      ; 1:5\n\
+             stack [Int(1)]\n\
+             0006 OP_ADD                      ; 1:1\n\
+             0 = Int(1)\n\
+             1 = Int(2)\n\
+             #0 0006 OP_ADD                      ; 1:1\n\
+             halted, result Some(Int(3))\n"
+        );
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let source = "1 +\n  (2 * 3)\n  - 4";
+        let output = session(source, "b 2\nb *14\nc\np\nd 2\nc\nd *14\nc");
+        assert_eq!(
+            output,
+            "0000 OP_CONSTANT      0 (1)      ; 1:1\n\
+             breakpoint at 0003\n\
+             breakpoint at 0014\n\
+             0003 OP_CONSTANT      1 (2)      ; 2:4\n\
+             stack [Int(1)]\n\
+             0014 OP_SUB                      ; 1:1\n\
+             halted, result Some(Int(3))\n"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let output = session("4 / 0", "b 2\nb *1\nb x\nd 1\nfly\nc\np");
+        assert_eq!(
+            output,
+            "0000 OP_CONSTANT      0 (4)      ; 1:1\n\
+             error: no instructions on line 2\n\
+             error: no instruction starts at offset 1\n\
+             error: expected a line or *<offset>: invalid digit found in string\n\
+             error: no breakpoint at 1\n\
+             error: unknown command \"fly\"\n\
+             error: at 1:1: division by zero\n\
+             stack []\n"
+        );
+    }
+}
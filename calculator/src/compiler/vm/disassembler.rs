@@ -0,0 +1,149 @@
+// Human-readable listing of bytecode: one instruction per line with its byte
+// offset, name, operand and, for constant loads, the constant's value:
+//
+//   0000 OP_CONSTANT      1 (2.5)
+//   0003 OP_ADD
+//
+// With a source map, the first instruction of each run is followed by the
+// line and column it was compiled from:
+//
+//   0000 OP_CONSTANT      0 (1)      ; 1:2
+//   0003 OP_MINUS                    ; 1:1
+use crate::compiler::vm::bytecode::Bytecode;
+use crate::dump::float_atom;
+use crate::primitive::PrimitiveType;
+use anyhow::{Context, Result};
+use std::fmt::Write;
+
+impl Bytecode {
+    pub fn disassemble(&self) -> Result<String> {
+        let mut listing = String::new();
+        let mut offset = 0;
+        let mut runs = self.source_map.runs().iter().peekable();
+        for op in self.decode()? {
+            let mut line = String::new();
+            match op.constant_index() {
+                Some(index) => {
+                    let value = self.constants.get(index as usize).with_context(|| {
+                        format!("{} loads missing constant {}", op.name(), index)
+                    })?;
+                    let value = constant(*value);
+                    write!(
+                        line,
+                        "{:04} {:<16} {} ({})",
+                        offset,
+                        op.name(),
+                        index,
+                        value
+                    )
+                }
+                None => write!(line, "{:04} {}", offset, op.name()),
+            }
+            .unwrap();
+            if let Some(run) = runs.next_if(|run| run.offset == offset) {
+                line = format!("{:<32} ; {}", line, run.span);
+            }
+            writeln!(listing, "{}", line).unwrap();
+            offset += 1 + op.operand_width();
+        }
+        Ok(listing)
+    }
+}
+
+fn constant(value: PrimitiveType) -> String {
+    match value {
+        PrimitiveType::Int(n) => n.to_string(),
+        PrimitiveType::Float(f) => float_atom(f),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compile;
+    use crate::compiler::vm::bytecode::Interpreter;
+    use crate::compiler::vm::opcode::OpCode;
+    use crate::optimizer::OptLevel;
+
+    #[test]
+    fn test_disassemble() {
+        let bytecode = Interpreter::from_source("-1 + 2.0").unwrap().unwrap();
+        assert_eq!(
+            bytecode.disassemble().unwrap(),
+            "0000 OP_CONSTANT      0 (1)\n\
+             0003 OP_MINUS\n\
+             0004 OP_CONSTANT      1 (2.0)\n\
+             0007 OP_ADD\n\
+             0008 OP_RETURN\n"
+        );
+    }
+
+    #[test]
+    fn test_source_map() {
+        let bytecode = Interpreter::from_source_mapped("-1 +\n  2.0", OptLevel::O0).unwrap();
+        assert_eq!(
+            bytecode.disassemble().unwrap(),
+            "0000 OP_CONSTANT      0 (1)      ; 1:2\n\
+             0003 OP_MINUS                    ; 1:1\n\
+             0004 OP_CONSTANT      1 (2.0)    ; 2:3\n\
+             0007 OP_ADD                      ; 1:1\n\
+             0008 OP_RETURN\n"
+        );
+    }
+
+    #[test]
+    fn test_every_opcode() {
+        let mut constants = vec![PrimitiveType::Int(0); 65537];
+        constants[1] = PrimitiveType::Float(-f64::INFINITY);
+        constants[65536] = PrimitiveType::Float(f64::NAN);
+        let ops = [
+            OpCode::Constant(1),
+            OpCode::ConstantLong(65536),
+            OpCode::Add,
+            OpCode::Constant(0),
+            OpCode::Sub,
+            OpCode::Constant(0),
+            OpCode::Mul,
+            OpCode::Constant(0),
+            OpCode::Div,
+            OpCode::Plus,
+            OpCode::Minus,
+            OpCode::Pop,
+            OpCode::Return,
+        ];
+        let bytecode = Bytecode::from_ops(&ops, constants);
+        assert_eq!(
+            bytecode.disassemble().unwrap(),
+            "0000 OP_CONSTANT      1 (-inf)\n\
+             0003 OP_CONSTANT_LONG 65536 (nan)\n\
+             0008 OP_ADD\n\
+             0009 OP_CONSTANT      0 (0)\n\
+             0012 OP_SUB\n\
+             0013 OP_CONSTANT      0 (0)\n\
+             0016 OP_MUL\n\
+             0017 OP_CONSTANT      0 (0)\n\
+             0020 OP_DIV\n\
+             0021 OP_PLUS\n\
+             0022 OP_MINUS\n\
+             0023 OP_POP\n\
+             0024 OP_RETURN\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_bytecode() {
+        let truncated = Bytecode {
+            instructions: vec![0x02, 0x01],
+            ..Bytecode::default()
+        };
+        assert_eq!(
+            truncated.disassemble().unwrap_err().to_string(),
+            "at offset 1"
+        );
+        let missing = Bytecode::from_ops(&[OpCode::Constant(3)], vec![]);
+        assert_eq!(
+            missing.disassemble().unwrap_err().to_string(),
+            "OP_CONSTANT loads missing constant 3"
+        );
+    }
+}
@@ -0,0 +1,311 @@
+// The `.gklc` binary format, so a program can be compiled once and run many
+// times. All integers are big-endian, like instruction operands.
+//
+//   magic        b"GKLC"
+//   version      u16
+//   flags        u16, FLAG_DEBUG marks a trailing debug section
+//   constants    u32 count, then per constant a tag byte and its value:
+//                TAG_INT with an i32, TAG_FLOAT with the f64's bits as a u64
+//   instructions u32 length, then the instruction bytes
+//   debug        u32 length, then the section's bytes: the source map as a
+//                u32 count, then per run its offset and its span's line,
+//                column, start and end, each a u32
+//
+// Reading is strict: truncated input, trailing bytes, unknown flags or tags
+// and bytecode the verifier rejects are errors.
+use crate::ast::Span;
+use crate::compiler::vm::bytecode::Bytecode;
+use crate::compiler::vm::source_map::{Run, SourceMap};
+use crate::compiler::vm::verifier;
+use crate::primitive::PrimitiveType;
+use anyhow::{Context, Result, bail};
+use std::io::{Read, Write};
+
+pub const MAGIC: &[u8; 4] = b"GKLC";
+// Bumped whenever the layout changes, since reading is strict: a file with a
+// section a reader doesn't know about must not reach that reader.
+pub const VERSION: u16 = 1;
+
+const FLAG_DEBUG: u16 = 1;
+
+const TAG_INT: u8 = 0;
+const TAG_FLOAT: u8 = 1;
+
+impl Bytecode {
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        let mut out = Vec::new();
+        out.extend(MAGIC);
+        out.extend(VERSION.to_be_bytes());
+        let flags = if self.source_map.is_empty() {
+            0
+        } else {
+            FLAG_DEBUG
+        };
+        out.extend(flags.to_be_bytes());
+
+        out.extend(length(self.constants.len())?);
+        for constant in &self.constants {
+            match constant {
+                PrimitiveType::Int(n) => {
+                    out.push(TAG_INT);
+                    out.extend(n.to_be_bytes());
+                }
+                PrimitiveType::Float(f) => {
+                    out.push(TAG_FLOAT);
+                    out.extend(f.to_bits().to_be_bytes());
+                }
+            }
+        }
+        out.extend(length(self.instructions.len())?);
+        out.extend(&self.instructions);
+        if !self.source_map.is_empty() {
+            let debug = debug_section(&self.source_map)?;
+            out.extend(length(debug.len())?);
+            out.extend(debug);
+        }
+        writer.write_all(&out)?;
+        Ok(())
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> Result<Bytecode> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut input = Input {
+            bytes: &bytes,
+            offset: 0,
+        };
+        parse(&mut input).with_context(|| format!("invalid .gklc file at byte {}", input.offset))
+    }
+}
+
+// Whether `bytes` start like a `.gklc` file rather than source text.
+pub fn is_gklc(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn debug_section(source_map: &SourceMap) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    out.extend(length(source_map.runs().len())?);
+    for run in source_map.runs() {
+        let span = run.span;
+        for field in [run.offset, span.line, span.column, span.start, span.end] {
+            out.extend(
+                u32::try_from(field)
+                    .context("source map too large")?
+                    .to_be_bytes(),
+            );
+        }
+    }
+    Ok(out)
+}
+
+fn length(len: usize) -> Result<[u8; 4]> {
+    Ok(u32::try_from(len)
+        .context("section too large")?
+        .to_be_bytes())
+}
+
+fn parse(input: &mut Input) -> Result<Bytecode> {
+    if input.take(MAGIC.len())? != MAGIC {
+        bail!("not a .gklc file");
+    }
+    let version = input.u16()?;
+    if version != VERSION {
+        bail!("unsupported version {}, expected {}", version, VERSION);
+    }
+    let flags = input.u16()?;
+    if flags & !FLAG_DEBUG != 0 {
+        bail!("unknown flags {:#06x}", flags);
+    }
+
+    let count = input.u32()?;
+    let mut constants = Vec::new();
+    for _ in 0..count {
+        let constant = match input.u8()? {
+            TAG_INT => PrimitiveType::Int(input.u32()? as i32),
+            TAG_FLOAT => PrimitiveType::Float(f64::from_bits(input.u64()?)),
+            tag => bail!("unknown constant tag {}", tag),
+        };
+        constants.push(constant);
+    }
+    let len = input.u32()? as usize;
+    let instructions = input.take(len)?.to_vec();
+    let mut source_map = SourceMap::new();
+    if flags & FLAG_DEBUG != 0 {
+        let len = input.u32()? as usize;
+        let end = input.offset.saturating_add(len);
+        source_map = parse_debug(input)?;
+        if input.offset != end {
+            bail!("wrong debug section length {}", len);
+        }
+    }
+    if input.offset != input.bytes.len() {
+        bail!("trailing bytes");
+    }
+
+    let bytecode = Bytecode {
+        instructions,
+        constants,
+        source_map,
+    };
+    verifier::verify(&bytecode)?;
+    Ok(bytecode)
+}
+
+fn parse_debug(input: &mut Input) -> Result<SourceMap> {
+    let count = input.u32()?;
+    let mut runs = Vec::new();
+    for _ in 0..count {
+        let mut field = || Ok::<_, anyhow::Error>(input.u32()? as usize);
+        let offset = field()?;
+        let span = Span {
+            line: field()?,
+            column: field()?,
+            start: field()?,
+            end: field()?,
+        };
+        runs.push(Run { offset, span });
+    }
+    Ok(SourceMap::from_runs(runs))
+}
+
+struct Input<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Input<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let Some(bytes) = self.bytes.get(self.offset..self.offset.saturating_add(len)) else {
+            bail!("unexpected end of file");
+        };
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compile;
+    use crate::compiler::vm::bytecode::Interpreter;
+    use crate::compiler::vm::opcode::OpCode;
+    use crate::optimizer::OptLevel;
+    use crate::primitive::ConstantKey;
+
+    fn write(bytecode: &Bytecode) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytecode.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn read(bytes: &[u8]) -> Result<Bytecode> {
+        Bytecode::read_from(&mut &bytes[..])
+    }
+
+    #[test]
+    fn test_layout() {
+        let bytecode = Interpreter::from_source("-1 + 2.5").unwrap().unwrap();
+        let bytes = write(&bytecode);
+        #[rustfmt::skip]
+        let expected = [
+            b'G', b'K', b'L', b'C', 0, 1, 0, 0,
+            0, 0, 0, 2,
+            TAG_INT, 0, 0, 0, 1,
+            TAG_FLOAT, 0x40, 0x04, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 9,
+            0x01, 0, 0, 0x0B, 0x01, 0, 1, 0x03, 0x0C,
+        ];
+        assert_eq!(bytes, expected);
+        assert!(is_gklc(&bytes));
+        assert_eq!(read(&bytes).unwrap(), bytecode);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut interpreter = Interpreter::new();
+        for value in [i32::MIN.into(), (-0.0).into(), f64::NAN.into()] {
+            interpreter.add_constant_instruction(value);
+            interpreter.add_instruction(OpCode::Pop);
+        }
+        let bytecode = interpreter.bytecode;
+        let back = read(&write(&bytecode)).unwrap();
+        assert_eq!(back.instructions, bytecode.instructions);
+        // Compared by bits, since NaN != NaN.
+        let keys = |b: &Bytecode| {
+            b.constants
+                .iter()
+                .map(|&c| ConstantKey::from(c))
+                .collect::<Vec<_>>()
+        };
+        assert!(keys(&back) == keys(&bytecode));
+    }
+
+    #[test]
+    fn test_debug_section() {
+        let bytecode = Interpreter::from_source_mapped("-1", OptLevel::O0).unwrap();
+        let bytes = write(&bytecode);
+        assert_eq!(bytes[7], FLAG_DEBUG as u8);
+        #[rustfmt::skip]
+        let debug = [
+            0, 0, 0, 44,
+            0, 0, 0, 2,
+            0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2,
+            0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2,
+        ];
+        assert!(bytes.ends_with(&debug));
+        assert_eq!(read(&bytes).unwrap(), bytecode);
+
+        let mut wrong_length = bytes.clone();
+        let at = bytes.len() - debug.len() + 3;
+        wrong_length[at] = 40;
+        assert!(read(&wrong_length).is_err());
+    }
+
+    #[test]
+    fn test_rejects_corrupt_input() {
+        let bytes = write(&Interpreter::from_source("1 + 2").unwrap().unwrap());
+        for len in 0..bytes.len() {
+            assert!(read(&bytes[..len]).is_err(), "accepted {} bytes", len);
+        }
+
+        let corrupt = |offset: usize, byte: u8| {
+            let mut bytes = bytes.clone();
+            bytes[offset] = byte;
+            read(&bytes).unwrap_err().root_cause().to_string()
+        };
+        assert_eq!(corrupt(0, b'X'), "not a .gklc file");
+        assert_eq!(corrupt(5, 2), "unsupported version 2, expected 1");
+        assert_eq!(corrupt(7, 4), "unknown flags 0x0004");
+        assert_eq!(corrupt(12, 9), "unknown constant tag 9");
+        // The first instruction loads constant 0; make it load constant 5.
+        assert_eq!(
+            corrupt(28, 5),
+            "OP_CONSTANT at offset 0 loads constant 5, but there are only 2"
+        );
+        assert_eq!(corrupt(26, 0xFF), "unknown opcode 0xff");
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            read(&trailing).unwrap_err().to_string(),
+            format!("invalid .gklc file at byte {}", bytes.len())
+        );
+    }
+}
@@ -1,3 +1,15 @@
-mod bytecode;
-mod opcode;
+pub mod bytecode;
+pub mod debugger;
+pub mod disassembler;
+pub mod gklc;
+pub mod opcode;
+pub mod peephole;
+pub mod profiler;
+pub mod source_map;
+#[cfg(feature = "trace")]
+pub mod trace;
+#[cfg(feature = "nan-boxing")]
+pub mod value;
+pub mod verifier;
+#[allow(clippy::module_inception)]
 pub mod vm;
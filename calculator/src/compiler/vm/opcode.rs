@@ -1,27 +1,160 @@
-// Operation Code
-#[derive(Debug, Copy, Clone)]
-// VM Operation Code
-pub enum OpCode {
-    OpConstant(u16), // pointer to constant table
-    OpPop,           // pop is needed for execution
-    OpAdd,
-    OpSub,
-    OpMul,
-    OpDiv,
-    OpPlus,
-    OpMinus,
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+// The opcode table: every instruction's encoding, operand and name comes from
+// here. Operands are unsigned integers encoded big-endian after the opcode.
+macro_rules! opcodes {
+    (
+        with_operand {
+            $($operand_op:ident($operand:ty) = $operand_code:literal => $operand_name:literal,)*
+        }
+        without_operand { $($op:ident = $code:literal => $name:literal,)* }
+    ) => {
+        // VM Operation Code
+        #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+        pub enum OpCode {
+            $(#[serde(rename = $operand_name)] $operand_op($operand),)*
+            $(#[serde(rename = $name)] $op,)*
+        }
+
+        pub fn make_op(op: OpCode) -> Vec<u8> {
+            match op {
+                $(OpCode::$operand_op(arg) => {
+                    let mut bytes = vec![$operand_code];
+                    bytes.extend(arg.to_be_bytes());
+                    bytes
+                })*
+                $(OpCode::$op => vec![$code],)*
+            }
+        }
+
+        impl OpCode {
+            pub const NAMES: &'static [&'static str] = &[
+                $($operand_name,)*
+                $($name,)*
+            ];
+
+            // Decodes the instruction at the start of `bytes`, returning it
+            // with its encoded length.
+            #[inline]
+            pub fn decode(bytes: &[u8]) -> Result<(OpCode, usize)> {
+                match Self::try_decode(bytes) {
+                    Some(decoded) => Ok(decoded),
+                    None => Err(Self::decode_error(bytes)),
+                }
+            }
+
+            // Like `decode`, for bytecode the verifier has accepted. It is
+            // cheap enough to run in the VM's dispatch loop.
+            #[inline(always)]
+            pub fn decode_verified(bytes: &[u8]) -> (OpCode, usize) {
+                match Self::try_decode(bytes) {
+                    Some(decoded) => decoded,
+                    None => unreachable!("{}", Self::decode_error(bytes)),
+                }
+            }
+
+            #[inline(always)]
+            fn try_decode(bytes: &[u8]) -> Option<(OpCode, usize)> {
+                match bytes.first()? {
+                    $($operand_code => {
+                        const WIDTH: usize = std::mem::size_of::<$operand>();
+                        let operand = bytes.get(1..1 + WIDTH)?.try_into().unwrap();
+                        Some((OpCode::$operand_op(<$operand>::from_be_bytes(operand)), 1 + WIDTH))
+                    })*
+                    $($code => Some((OpCode::$op, 1)),)*
+                    _ => None,
+                }
+            }
+
+            // Why `try_decode` failed. Building the error is kept out of the
+            // way of the verifier's loop.
+            #[cold]
+            fn decode_error(bytes: &[u8]) -> anyhow::Error {
+                match bytes.first() {
+                    None => anyhow!("expected an instruction, found end of bytecode"),
+                    $(Some($operand_code) => {
+                        anyhow!("truncated operand for {}", $operand_name)
+                    })*
+                    Some(other) => anyhow!("unknown opcode {:#04x}", other),
+                }
+            }
+
+            // Size of the operand in bytes, after the opcode byte.
+            pub fn operand_width(&self) -> usize {
+                match self {
+                    $(OpCode::$operand_op(_) => std::mem::size_of::<$operand>(),)*
+                    $(OpCode::$op => 0,)*
+                }
+            }
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(OpCode::$operand_op(_) => $operand_name,)*
+                    $(OpCode::$op => $name,)*
+                }
+            }
+
+            pub fn from_name(name: &str, operand: Option<u32>) -> Result<OpCode> {
+                let op = match (name, operand) {
+                    $(($operand_name, Some(arg)) => {
+                        OpCode::$operand_op(<$operand>::try_from(arg)?)
+                    })*
+                    $(($name, None) => OpCode::$op,)*
+                    _ => bail!("unknown instruction {} {:?}", name, operand),
+                };
+                Ok(op)
+            }
+        }
+    };
+}
+
+opcodes! {
+    with_operand {
+        // pointer to constant table
+        Constant(u16) = 0x01 => "OP_CONSTANT",
+        // for constant tables beyond u16 indices
+        ConstantLong(u32) = 0x07 => "OP_CONSTANT_LONG",
+    }
+    without_operand {
+        // pop is needed for execution
+        Pop = 0x02 => "OP_POP",
+        Add = 0x03 => "OP_ADD",
+        Sub = 0x04 => "OP_SUB",
+        Mul = 0x05 => "OP_MUL",
+        Div = 0x06 => "OP_DIV",
+        Plus = 0x0A => "OP_PLUS",
+        Minus = 0x0B => "OP_MINUS",
+        // pops the program's result and stops
+        Return = 0x0C => "OP_RETURN",
+    }
 }
 
-pub fn make_op(op: OpCode) -> Vec<u8> {
-    match op {
-        OpCode::OpConstant(arg) => vec![0x01, (arg >> 8) as u8, (arg & 0xff) as u8],
-        OpCode::OpPop => vec![0x02],
-        OpCode::OpAdd => vec![0x03],
-        OpCode::OpSub => vec![0x04],
-        OpCode::OpMul => vec![0x05],
-        OpCode::OpDiv => vec![0x06],
-        OpCode::OpPlus => vec![0x0A],
-        OpCode::OpMinus => vec![0x0B],
+impl OpCode {
+    // Loads constant `index`, using the short form while the index fits.
+    pub fn constant(index: u32) -> OpCode {
+        match u16::try_from(index) {
+            Ok(index) => OpCode::Constant(index),
+            Err(_) => OpCode::ConstantLong(index),
+        }
+    }
+
+    // How many values the instruction pops, and how many it then pushes.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            OpCode::Constant(_) | OpCode::ConstantLong(_) => (0, 1),
+            OpCode::Pop | OpCode::Return => (1, 0),
+            OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div => (2, 1),
+            OpCode::Plus | OpCode::Minus => (1, 1),
+        }
+    }
+
+    pub fn constant_index(&self) -> Option<u32> {
+        match self {
+            OpCode::Constant(index) => Some(u32::from(*index)),
+            OpCode::ConstantLong(index) => Some(*index),
+            _ => None,
+        }
     }
 }
 
@@ -32,9 +165,54 @@ mod tests {
 
     #[test]
     fn test_make_op() {
-        assert_eq!(make_op(OpCode::OpConstant(1)), vec![0x01, 0, 1]);
-        assert_eq!(make_op(OpCode::OpConstant(257)), vec![0x01, 1, 1]);
-        assert_eq!(make_op(OpCode::OpPop), vec![0x02]);
-        assert_eq!(make_op(OpCode::OpMinus), vec![0x0B]);
+        assert_eq!(make_op(OpCode::Constant(1)), vec![0x01, 0, 1]);
+        assert_eq!(make_op(OpCode::Constant(257)), vec![0x01, 1, 1]);
+        assert_eq!(make_op(OpCode::ConstantLong(65536)), vec![0x07, 0, 1, 0, 0]);
+        assert_eq!(make_op(OpCode::Pop), vec![0x02]);
+        assert_eq!(make_op(OpCode::Minus), vec![0x0B]);
+    }
+
+    #[test]
+    fn test_decode() {
+        for op in [
+            OpCode::Constant(257),
+            OpCode::ConstantLong(70000),
+            OpCode::Pop,
+            OpCode::Minus,
+        ] {
+            let bytes = make_op(op);
+            assert_eq!(OpCode::decode(&bytes).unwrap(), (op, bytes.len()));
+            assert_eq!(OpCode::decode_verified(&bytes), (op, bytes.len()));
+        }
+        assert!(OpCode::decode(&[0x01, 0]).is_err());
+        assert!(OpCode::decode(&[0x07, 0, 1, 0]).is_err());
+        assert!(OpCode::decode(&[0xFF]).is_err());
+        assert!(OpCode::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn test_table() {
+        let ops = [
+            OpCode::Constant(1),
+            OpCode::ConstantLong(1),
+            OpCode::Pop,
+            OpCode::Add,
+            OpCode::Sub,
+            OpCode::Mul,
+            OpCode::Div,
+            OpCode::Plus,
+            OpCode::Minus,
+            OpCode::Return,
+        ];
+        for op in ops {
+            assert_eq!(make_op(op).len(), 1 + op.operand_width());
+            let operand = op.constant_index();
+            assert_eq!(OpCode::from_name(op.name(), operand).unwrap(), op);
+            assert!(OpCode::NAMES.contains(&op.name()));
+        }
+        // Every name is in `ops`, so a new opcode has to be added there too.
+        assert_eq!(OpCode::NAMES.len(), ops.len());
+        assert!(OpCode::from_name("OP_CONSTANT", Some(65536)).is_err());
+        assert!(OpCode::from_name("OP_ADD", Some(1)).is_err());
     }
 }
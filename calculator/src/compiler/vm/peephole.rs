@@ -0,0 +1,273 @@
+// Peephole pass over compiled bytecode. It drops no-op `OP_PLUS`es and
+// evaluates operators whose operands are all constants, then rebuilds the
+// constant table from the constants still in use. The bytecode is verified
+// first: folding appends to the constant table, so an out-of-range index
+// would otherwise end up loading a folded value.
+//
+// The pass works on decoded instructions and re-encodes them at the end, so
+// any offsets into `instructions` change and the source map is rebuilt: a
+// folded constant is mapped to the operator it replaces. There are no jumps
+// yet; once there are, their targets have to be remapped here too, and
+// instructions after an unconditional jump can be dropped as dead code.
+use crate::ast::{Operator, Span};
+use crate::compiler::vm::bytecode::Bytecode;
+use crate::compiler::vm::opcode::OpCode;
+use crate::compiler::vm::source_map::SourceMap;
+use crate::compiler::vm::verifier;
+use crate::primitive::{ConstantKey, PrimitiveType};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+pub fn optimize(bytecode: &Bytecode) -> Result<Bytecode> {
+    let mut constants = bytecode.constants.clone();
+    let mut out: Vec<OpCode> = Vec::new();
+    // The span of each of `out`, if it has one.
+    let mut spans: Vec<Option<Span>> = Vec::new();
+    let mut offset = 0;
+    for op in verifier::verify(bytecode)?.code {
+        let span = bytecode.source_map.lookup(offset);
+        offset += 1 + op.operand_width();
+        // How many of `out` the instruction replaces, and with what.
+        let (replaced, replacement) = match op {
+            OpCode::Plus => (0, None),
+            OpCode::Minus => match trailing_constants::<1>(&out, &constants) {
+                Some([value]) => (1, Some(push_constant(&mut constants, -value))),
+                None => (0, Some(op)),
+            },
+            OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div => {
+                let operator = match op {
+                    OpCode::Add => Operator::Plus,
+                    OpCode::Sub => Operator::Minus,
+                    OpCode::Mul => Operator::Multiply,
+                    _ => Operator::Divide,
+                };
+                // Int division by zero is left to fail at runtime.
+                let folded = trailing_constants::<2>(&out, &constants)
+                    .and_then(|[lhs, rhs]| lhs.checked_binary(operator, rhs));
+                match folded {
+                    Some(value) => (2, Some(push_constant(&mut constants, value))),
+                    None => (0, Some(op)),
+                }
+            }
+            _ => (0, Some(op)),
+        };
+        out.truncate(out.len() - replaced);
+        spans.truncate(out.len());
+        if let Some(op) = replacement {
+            out.push(op);
+            spans.push(span);
+        }
+    }
+    let compacted = compact(&out, &constants)?;
+    // Constant indices change, and so may the width of their instructions.
+    let mut source_map = SourceMap::new();
+    let mut offset = 0;
+    for (op, span) in compacted.decode()?.into_iter().zip(spans) {
+        if let Some(span) = span {
+            source_map.push(offset, span);
+        }
+        offset += 1 + op.operand_width();
+    }
+    Ok(Bytecode {
+        source_map,
+        ..compacted
+    })
+}
+
+// The values loaded by the last `N` instructions, if those are all constants.
+fn trailing_constants<const N: usize>(
+    ops: &[OpCode],
+    constants: &[PrimitiveType],
+) -> Option<[PrimitiveType; N]> {
+    let tail = ops.get(ops.len().checked_sub(N)?..)?;
+    let mut values = [PrimitiveType::Int(0); N];
+    for (value, op) in values.iter_mut().zip(tail) {
+        *value = *constants.get(op.constant_index()? as usize)?;
+    }
+    Some(values)
+}
+
+fn push_constant(constants: &mut Vec<PrimitiveType>, value: PrimitiveType) -> OpCode {
+    constants.push(value);
+    OpCode::constant((constants.len() - 1) as u32)
+}
+
+// Keeps only the constants `ops` still load, deduplicated and numbered in
+// order of first use.
+fn compact(ops: &[OpCode], constants: &[PrimitiveType]) -> Result<Bytecode> {
+    let mut used = Vec::new();
+    let mut indices = HashMap::new();
+    let ops = ops
+        .iter()
+        .map(|op| {
+            let Some(index) = op.constant_index() else {
+                return Ok(*op);
+            };
+            let value = constants
+                .get(index as usize)
+                .with_context(|| format!("{} loads missing constant {}", op.name(), index))?;
+            let index = *indices.entry(ConstantKey::from(*value)).or_insert_with(|| {
+                used.push(*value);
+                (used.len() - 1) as u32
+            });
+            Ok(OpCode::constant(index))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Bytecode::from_ops(&ops, used))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compile;
+    use crate::compiler::vm::bytecode::Interpreter;
+    use crate::compiler::vm::vm::VM;
+    use crate::optimizer::OptLevel;
+    use crate::testing::{self, same_value};
+    use proptest::prelude::*;
+
+    fn listing(bytecode: &Bytecode) -> String {
+        bytecode.disassemble().unwrap()
+    }
+
+    fn before_and_after(source: &str) -> (String, String) {
+        let bytecode = Interpreter::from_source(source).unwrap().unwrap();
+        let optimized = optimize(&bytecode).unwrap();
+        (listing(&bytecode), listing(&optimized))
+    }
+
+    #[test]
+    fn test_removes_unary_plus() {
+        let (before, after) = before_and_after("+(1 / 0)");
+        assert_eq!(
+            before,
+            "0000 OP_CONSTANT      0 (1)\n\
+             0003 OP_CONSTANT      1 (0)\n\
+             0006 OP_DIV\n\
+             0007 OP_PLUS\n\
+             0008 OP_RETURN\n"
+        );
+        assert_eq!(
+            after,
+            "0000 OP_CONSTANT      0 (1)\n\
+             0003 OP_CONSTANT      1 (0)\n\
+             0006 OP_DIV\n\
+             0007 OP_RETURN\n"
+        );
+    }
+
+    #[test]
+    fn test_folds_negated_constants() {
+        let (before, after) = before_and_after("-5 * 1.5");
+        assert_eq!(
+            before,
+            "0000 OP_CONSTANT      0 (5)\n\
+             0003 OP_MINUS\n\
+             0004 OP_CONSTANT      1 (1.5)\n\
+             0007 OP_MUL\n\
+             0008 OP_RETURN\n"
+        );
+        assert_eq!(
+            after,
+            "0000 OP_CONSTANT      0 (-7.5)\n\
+             0003 OP_RETURN\n"
+        );
+    }
+
+    #[test]
+    fn test_folds_binary_operators() {
+        let (before, after) = before_and_after("1 + 2 - (3 * 3)");
+        assert_eq!(
+            before,
+            "0000 OP_CONSTANT      0 (1)\n\
+             0003 OP_CONSTANT      1 (2)\n\
+             0006 OP_ADD\n\
+             0007 OP_CONSTANT      2 (3)\n\
+             0010 OP_CONSTANT      2 (3)\n\
+             0013 OP_MUL\n\
+             0014 OP_SUB\n\
+             0015 OP_RETURN\n"
+        );
+        assert_eq!(
+            after,
+            "0000 OP_CONSTANT      0 (-6)\n\
+             0003 OP_RETURN\n"
+        );
+    }
+
+    #[test]
+    fn test_keeps_division_by_zero() {
+        let (before, after) = before_and_after("(2 + 2) / (1 - 1) + 3");
+        assert_eq!(
+            after,
+            "0000 OP_CONSTANT      0 (4)\n\
+             0003 OP_CONSTANT      1 (0)\n\
+             0006 OP_DIV\n\
+             0007 OP_CONSTANT      2 (3)\n\
+             0010 OP_ADD\n\
+             0011 OP_RETURN\n"
+        );
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn test_keeps_constants_across_statements() {
+        // The pass never looks past an `OP_POP`.
+        let bytecode = Bytecode::from_ops(
+            &[
+                OpCode::Constant(0),
+                OpCode::Constant(0),
+                OpCode::Pop,
+                OpCode::Constant(0),
+                OpCode::Add,
+                OpCode::Pop,
+            ],
+            vec![1.into()],
+        );
+        assert_eq!(optimize(&bytecode).unwrap(), bytecode);
+    }
+
+    #[test]
+    fn test_rejects_unverified_bytecode() {
+        // Folding `1 + 2` appends constant 2, which the second `OP_CONSTANT`
+        // must not pick up.
+        let bytecode = Bytecode::from_sexpr(
+            "(bytecode (constants (int 1) (int 2)) (instructions (OP_CONSTANT 0) \
+             (OP_CONSTANT 1) OP_ADD (OP_CONSTANT 2) OP_ADD OP_RETURN))",
+        )
+        .unwrap();
+        assert_eq!(
+            optimize(&bytecode).unwrap_err().to_string(),
+            "OP_CONSTANT at offset 7 loads constant 2, but there are only 2"
+        );
+        assert!(compact(&[OpCode::Constant(1)], &[1.into()]).is_err());
+    }
+
+    #[test]
+    fn test_remaps_source_map() {
+        let bytecode = Interpreter::from_source_mapped("+2 * 3 / (0 * 1)", OptLevel::O0).unwrap();
+        let optimized = optimize(&bytecode).unwrap();
+        assert_eq!(
+            listing(&optimized),
+            "0000 OP_CONSTANT      0 (6)      ; 1:1\n\
+             0003 OP_CONSTANT      1 (0)      ; 1:11\n\
+             0006 OP_DIV                      ; 1:1\n\
+             0007 OP_RETURN\n"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_peephole_preserves_result(node in testing::program()) {
+            // Skip programs that fail at runtime.
+            prop_assume!(testing::eval(&node).is_some());
+            let bytecode = Interpreter::from_ast(vec![node]).unwrap();
+            let optimized = optimize(&bytecode).unwrap();
+            // Without a runtime failure the whole statement is constant.
+            prop_assert_eq!(optimized.decode().unwrap().len(), 2);
+            let expected = VM::execute(bytecode).unwrap();
+            let result = VM::execute(optimized).unwrap();
+            prop_assert!(same_value(result, expected), "{:?} != {:?}", result, expected);
+        }
+    }
+}
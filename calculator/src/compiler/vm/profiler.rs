@@ -0,0 +1,226 @@
+// Profiles a run by stepping the VM and timing every step: how often each
+// opcode and each instruction ran, and how long each class of instruction
+// took. Timings include the clock reads and `step`'s stack checks, so they
+// are for comparing with each other, not with an uninstrumented `run`.
+//
+// Hot spots are reported by byte offset and, if the bytecode has a source
+// map, by source line. There are no function calls yet, so every folded stack
+// is the single frame `main`.
+use crate::compiler::vm::opcode::OpCode;
+use crate::compiler::vm::vm::VM;
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counter {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl Counter {
+    fn add(&mut self, time: Duration) {
+        self.count += 1;
+        self.time += time;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Profile {
+    pub opcodes: HashMap<&'static str, Counter>,
+    pub classes: HashMap<&'static str, Counter>,
+    // Each instruction that ran, by byte offset.
+    pub offsets: BTreeMap<usize, (OpCode, Counter)>,
+    // By the source line each instruction was compiled from, if known.
+    pub lines: BTreeMap<usize, Counter>,
+    pub total: Duration,
+}
+
+pub fn class(op: OpCode) -> &'static str {
+    match op {
+        OpCode::Constant(_) | OpCode::ConstantLong(_) => "load",
+        OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div => "arithmetic",
+        OpCode::Plus | OpCode::Minus => "unary",
+        OpCode::Pop | OpCode::Return => "stack",
+    }
+}
+
+impl VM {
+    // Runs the program from the start, like `run`, and profiles it.
+    pub fn profile(&mut self) -> Result<Profile> {
+        self.reset()?;
+        let mut profile = Profile::default();
+        while let Some(op) = self.next_instruction() {
+            let offset = self.ip();
+            let start = Instant::now();
+            self.step()?;
+            let time = start.elapsed();
+            profile.record(offset, op, time);
+            if let Some(span) = self.source_map().lookup(offset) {
+                profile.lines.entry(span.line).or_default().add(time);
+            }
+        }
+        Ok(profile)
+    }
+}
+
+impl Profile {
+    fn record(&mut self, offset: usize, op: OpCode, time: Duration) {
+        self.opcodes.entry(op.name()).or_default().add(time);
+        self.classes.entry(class(op)).or_default().add(time);
+        self.offsets
+            .entry(offset)
+            .or_insert((op, Counter::default()))
+            .1
+            .add(time);
+        self.total += time;
+    }
+
+    // One table each for opcodes, classes, offsets and lines, hottest first.
+    pub fn table(&self) -> String {
+        let mut out = String::new();
+        self.section(&mut out, "opcode", &self.opcodes);
+        out.push('\n');
+        self.section(&mut out, "class", &self.classes);
+        out.push('\n');
+        let offsets: HashMap<String, Counter> = self
+            .offsets
+            .iter()
+            .map(|(offset, (op, counter))| (format!("{:04} {}", offset, op.name()), *counter))
+            .collect();
+        self.section(&mut out, "instruction", &offsets);
+        if !self.lines.is_empty() {
+            out.push('\n');
+            let lines: HashMap<String, Counter> = self
+                .lines
+                .iter()
+                .map(|(line, counter)| (line.to_string(), *counter))
+                .collect();
+            self.section(&mut out, "line", &lines);
+        }
+        out
+    }
+
+    fn section<K: AsRef<str> + Ord>(
+        &self,
+        out: &mut String,
+        heading: &str,
+        counters: &HashMap<K, Counter>,
+    ) {
+        let mut rows: Vec<_> = counters.iter().collect();
+        rows.sort_by(|(a, x), (b, y)| y.count.cmp(&x.count).then(a.cmp(b)));
+        writeln!(
+            out,
+            "{:<20} {:>10} {:>12} {:>6}",
+            heading, "count", "time", "%time"
+        )
+        .unwrap();
+        for (name, counter) in rows {
+            let share = if self.total.is_zero() {
+                0.0
+            } else {
+                100.0 * counter.time.as_secs_f64() / self.total.as_secs_f64()
+            };
+            writeln!(
+                out,
+                "{:<20} {:>10} {:>12} {:>5.1}%",
+                name.as_ref(),
+                counter.count,
+                format!("{:?}", counter.time),
+                share
+            )
+            .unwrap();
+        }
+    }
+
+    // Executions per opcode as folded stacks, the input format of
+    // flamegraph.pl and inferno.
+    pub fn folded(&self) -> String {
+        let mut opcodes: Vec<_> = self.opcodes.iter().collect();
+        opcodes.sort_by_key(|(name, _)| **name);
+        opcodes
+            .into_iter()
+            .map(|(name, counter)| format!("main;{} {}\n", name, counter.count))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compile;
+    use crate::compiler::vm::bytecode::Interpreter;
+    use crate::optimizer::OptLevel;
+
+    fn profile(source: &str) -> (VM, Profile) {
+        let bytecode = Interpreter::from_source(source).unwrap().unwrap();
+        let mut vm = VM::new(bytecode).unwrap();
+        let profile = vm.profile().unwrap();
+        (vm, profile)
+    }
+
+    #[test]
+    fn test_counts() {
+        // 0000 OP_CONSTANT 0, 0003 OP_CONSTANT 1, 0006 OP_ADD,
+        // 0007 OP_CONSTANT 2, 0010 OP_ADD, 0011 OP_RETURN
+        let (vm, profile) = profile("1 + 2 + 3");
+        assert_eq!(vm.result(), Some(6.into()));
+        let count = |counters: &HashMap<&str, Counter>, key| counters[key].count;
+        assert_eq!(count(&profile.opcodes, "OP_CONSTANT"), 3);
+        assert_eq!(count(&profile.opcodes, "OP_ADD"), 2);
+        assert_eq!(count(&profile.classes, "load"), 3);
+        assert_eq!(count(&profile.classes, "stack"), 1);
+        assert_eq!(
+            profile.offsets.keys().copied().collect::<Vec<_>>(),
+            [0, 3, 6, 7, 10, 11]
+        );
+        assert_eq!(profile.offsets[&6].0, OpCode::Add);
+        let time: Duration = profile.opcodes.values().map(|c| c.time).sum();
+        assert_eq!(time, profile.total);
+    }
+
+    #[test]
+    fn test_reports() {
+        let (_, profile) = profile("-1 + 2 + 3");
+        assert_eq!(
+            profile.folded(),
+            "main;OP_ADD 2\nmain;OP_CONSTANT 3\nmain;OP_MINUS 1\nmain;OP_RETURN 1\n"
+        );
+        let table = profile.table();
+        let first_column: Vec<_> = table
+            .lines()
+            .map(|line| line.split_whitespace().next().unwrap_or(""))
+            .collect();
+        assert_eq!(
+            first_column[..7],
+            [
+                "opcode",
+                "OP_CONSTANT",
+                "OP_ADD",
+                "OP_MINUS",
+                "OP_RETURN",
+                "",
+                "class"
+            ]
+        );
+        assert!(table.contains("0004 OP_CONSTANT"));
+        assert!(!table.contains("line"));
+    }
+
+    #[test]
+    fn test_lines() {
+        let bytecode =
+            Interpreter::from_source_mapped("1 +\n  (2 * 3)\n  - 4", OptLevel::O0).unwrap();
+        let mut vm = VM::new(bytecode).unwrap();
+        let profile = vm.profile().unwrap();
+        let counts: Vec<_> = profile
+            .lines
+            .iter()
+            .map(|(&line, c)| (line, c.count))
+            .collect();
+        // Line 1 has the 1, both operators and the return.
+        assert_eq!(counts, [(1, 4), (2, 3), (3, 1)]);
+        assert!(profile.table().contains("\nline "));
+    }
+}
@@ -0,0 +1,129 @@
+// Maps bytecode offsets back to the source spans they were compiled from, for
+// runtime errors, the disassembler and the profiler.
+//
+// Like CPython's `co_lnotab` it is run-length encoded: there is one run per
+// stretch of consecutive instructions compiled from the same span, giving the
+// offset the stretch starts at. Bytecode that wasn't compiled from source,
+// e.g. from the AST formats, has an empty map.
+use crate::ast::Span;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    pub offset: usize,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SourceMap {
+    runs: Vec<Run>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Runs must be in order of their offsets, see `verifier::verify`.
+    pub fn from_runs(runs: Vec<Run>) -> Self {
+        Self { runs }
+    }
+
+    pub fn runs(&self) -> &[Run] {
+        &self.runs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    // Maps the instruction at `offset`, and those after it up to the next
+    // run, to `span`.
+    pub fn push(&mut self, offset: usize, span: Span) {
+        if self.runs.last().is_none_or(|run| run.span != span) {
+            self.runs.push(Run { offset, span });
+        }
+    }
+
+    // The span of the instruction at `offset`.
+    pub fn lookup(&self, offset: usize) -> Option<Span> {
+        let index = self.runs.partition_point(|run| run.offset <= offset);
+        Some(self.runs.get(index.checked_sub(1)?)?.span)
+    }
+
+    // The offsets at which running enters `line`: the start of each run on
+    // it that doesn't follow another run on it. An operator can come back to
+    // its line after its operands on the next ones, e.g. the `+` in "1 +\n2".
+    pub fn line_starts(&self, line: usize) -> Vec<usize> {
+        let mut previous = None;
+        let mut offsets = Vec::new();
+        for run in &self.runs {
+            if run.span.line == line && previous != Some(line) {
+                offsets.push(run.offset);
+            }
+            previous = Some(run.span.line);
+        }
+        offsets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: usize) -> Span {
+        Span {
+            start,
+            end: start + 1,
+            line: 1,
+            column: start + 1,
+        }
+    }
+
+    #[test]
+    fn test_runs() {
+        let mut map = SourceMap::new();
+        assert_eq!(map.lookup(0), None);
+        map.push(0, span(0));
+        map.push(3, span(4));
+        map.push(6, span(4));
+        map.push(7, span(0));
+        assert_eq!(
+            map.runs(),
+            [
+                Run {
+                    offset: 0,
+                    span: span(0)
+                },
+                Run {
+                    offset: 3,
+                    span: span(4)
+                },
+                Run {
+                    offset: 7,
+                    span: span(0)
+                },
+            ]
+        );
+        assert_eq!(map.lookup(2), Some(span(0)));
+        assert_eq!(map.lookup(6), Some(span(4)));
+        assert_eq!(map.lookup(100), Some(span(0)));
+    }
+
+    #[test]
+    fn test_line_starts() {
+        let on_line = |line, start| Span {
+            line,
+            ..span(start)
+        };
+        let mut map = SourceMap::new();
+        map.push(0, on_line(1, 0));
+        map.push(3, on_line(2, 6));
+        map.push(6, on_line(2, 8));
+        map.push(9, on_line(1, 0));
+        assert_eq!(map.line_starts(1), [0, 9]);
+        assert_eq!(map.line_starts(2), [3]);
+        assert!(map.line_starts(3).is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+// Opt-in tracing of the instructions the VM runs, behind the trace feature.
+// Without the feature the VM has no tracer field and its loop no check.
+//
+// Each event carries the instruction's byte offset, the instruction and the
+// stack as it was before the instruction ran, oldest value first.
+use crate::compiler::vm::opcode::OpCode;
+use crate::primitive::PrimitiveType;
+use anyhow::{Result, bail};
+use std::collections::HashSet;
+use std::ops::Range;
+
+pub struct TraceEvent<'a> {
+    pub offset: usize,
+    pub op: OpCode,
+    pub stack: &'a [PrimitiveType],
+}
+
+// Where events go. Closures are sinks, e.g. to collect events in tests.
+pub trait TraceSink {
+    fn record(&mut self, event: &TraceEvent);
+}
+
+impl<F: FnMut(&TraceEvent)> TraceSink for F {
+    fn record(&mut self, event: &TraceEvent) {
+        self(event)
+    }
+}
+
+// Writes one line per event to stderr, e.g. `0003 OP_CONSTANT 1 [Int(1)]`.
+pub struct Stderr;
+
+impl TraceSink for Stderr {
+    fn record(&mut self, event: &TraceEvent) {
+        let operand = event
+            .op
+            .constant_index()
+            .map_or(String::new(), |index| format!(" {}", index));
+        eprintln!(
+            "{:04} {}{} {:?}",
+            event.offset,
+            event.op.name(),
+            operand,
+            event.stack
+        );
+    }
+}
+
+pub struct Tracer {
+    sink: Box<dyn TraceSink>,
+    opcodes: Option<HashSet<&'static str>>,
+    range: Option<Range<usize>>,
+}
+
+impl Tracer {
+    pub fn new(sink: impl TraceSink + 'static) -> Self {
+        Self {
+            sink: Box::new(sink),
+            opcodes: None,
+            range: None,
+        }
+    }
+
+    // Only traces instructions with these names, e.g. `["OP_ADD", "OP_SUB"]`.
+    pub fn opcodes(mut self, names: &[&str]) -> Result<Self> {
+        let mut opcodes = HashSet::new();
+        for name in names {
+            let Some(&name) = OpCode::NAMES.iter().find(|known| *known == name) else {
+                bail!("unknown instruction {}", name);
+            };
+            opcodes.insert(name);
+        }
+        self.opcodes = Some(opcodes);
+        Ok(self)
+    }
+
+    // Only traces instructions whose byte offset is in `range`.
+    pub fn range(mut self, range: Range<usize>) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    pub fn wants(&self, offset: usize, op: OpCode) -> bool {
+        self.range
+            .as_ref()
+            .is_none_or(|range| range.contains(&offset))
+            && self
+                .opcodes
+                .as_ref()
+                .is_none_or(|opcodes| opcodes.contains(op.name()))
+    }
+
+    pub fn record(&mut self, event: &TraceEvent) {
+        self.sink.record(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compile;
+    use crate::compiler::vm::bytecode::Interpreter;
+    use crate::compiler::vm::vm::VM;
+    use std::sync::{Arc, Mutex};
+
+    fn trace(source: &str, tracer: impl FnOnce(Tracer) -> Tracer) -> Vec<String> {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = {
+            let lines = lines.clone();
+            move |event: &TraceEvent| {
+                let line = format!("{} {} {:?}", event.offset, event.op.name(), event.stack);
+                lines.lock().unwrap().push(line);
+            }
+        };
+        let bytecode = Interpreter::from_source(source).unwrap().unwrap();
+        let mut vm = VM::new(bytecode).unwrap();
+        vm.set_tracer(Some(tracer(Tracer::new(sink))));
+        vm.run().unwrap();
+        lines.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn test_trace() {
+        assert_eq!(
+            trace("-2 + 1", |tracer| tracer),
+            [
+                "0 OP_CONSTANT []",
+                "3 OP_MINUS [Int(2)]",
+                "4 OP_CONSTANT [Int(-2)]",
+                "7 OP_ADD [Int(-2), Int(1)]",
+                "8 OP_RETURN [Int(-1)]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filters() {
+        let by_opcode = |tracer: Tracer| tracer.opcodes(&["OP_ADD", "OP_MINUS"]).unwrap();
+        assert_eq!(
+            trace("-2 + 1", by_opcode),
+            ["3 OP_MINUS [Int(2)]", "7 OP_ADD [Int(-2), Int(1)]"]
+        );
+        assert_eq!(
+            trace("-2 + 1", |tracer| tracer.range(3..7)),
+            ["3 OP_MINUS [Int(2)]", "4 OP_CONSTANT [Int(-2)]"]
+        );
+        assert!(Tracer::new(Stderr).opcodes(&["OpNope"]).is_err());
+    }
+}
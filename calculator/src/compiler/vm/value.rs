@@ -0,0 +1,206 @@
+// NaN-boxed VM value: 8 bytes instead of `PrimitiveType`'s 16.
+//
+// A Float is stored as its own bits. Everything else lives in the payload of
+// a positive quiet NaN, with a tag in bits 48..=50 and up to 48 bits of data
+// below it. To keep those encodings free, every NaN Float is stored as the
+// canonical quiet NaN of the same sign; arithmetic never observes a NaN's
+// payload, so results are the same as with `PrimitiveType`.
+use crate::primitive::PrimitiveType;
+use anyhow::{Result, anyhow};
+use std::fmt;
+
+const QNAN: u64 = 0x7FF8_0000_0000_0000;
+const SIGN: u64 = 1 << 63;
+const TAG_SHIFT: u32 = 48;
+const TAG_MASK: u64 = 0b111 << TAG_SHIFT;
+const PAYLOAD_MASK: u64 = (1 << TAG_SHIFT) - 1;
+
+const TAG_INT: u64 = 1;
+const TAG_BOOL: u64 = 2;
+const TAG_POINTER: u64 = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Value(u64);
+
+impl Value {
+    pub fn int(n: i32) -> Value {
+        Value::tagged(TAG_INT, u64::from(n as u32))
+    }
+
+    pub fn float(f: f64) -> Value {
+        if f.is_nan() {
+            Value(QNAN | (f.to_bits() & SIGN))
+        } else {
+            Value(f.to_bits())
+        }
+    }
+
+    pub fn bool(b: bool) -> Value {
+        Value::tagged(TAG_BOOL, u64::from(b))
+    }
+
+    // Heap pointers must fit in 48 bits, as on current x86-64 and AArch64.
+    pub fn pointer<T>(ptr: *const T) -> Option<Value> {
+        let address = ptr as usize as u64;
+        (address & !PAYLOAD_MASK == 0).then(|| Value::tagged(TAG_POINTER, address))
+    }
+
+    fn tagged(tag: u64, payload: u64) -> Value {
+        Value(QNAN | (tag << TAG_SHIFT) | payload)
+    }
+
+    fn tag(self) -> Option<u64> {
+        let is_boxed = self.0 & !TAG_MASK & !PAYLOAD_MASK == QNAN && self.0 & TAG_MASK != 0;
+        is_boxed.then_some((self.0 & TAG_MASK) >> TAG_SHIFT)
+    }
+
+    fn payload(self) -> u64 {
+        self.0 & PAYLOAD_MASK
+    }
+
+    pub fn as_int(self) -> Option<i32> {
+        (self.tag() == Some(TAG_INT)).then(|| self.payload() as u32 as i32)
+    }
+
+    pub fn as_float(self) -> Option<f64> {
+        self.tag().is_none().then(|| f64::from_bits(self.0))
+    }
+
+    pub fn as_bool(self) -> Option<bool> {
+        (self.tag() == Some(TAG_BOOL)).then(|| self.payload() != 0)
+    }
+
+    pub fn as_pointer<T>(self) -> Option<*const T> {
+        (self.tag() == Some(TAG_POINTER)).then(|| self.payload() as usize as *const T)
+    }
+
+    pub fn as_number(self) -> Option<PrimitiveType> {
+        match (self.as_int(), self.as_float()) {
+            (Some(n), _) => Some(PrimitiveType::Int(n)),
+            (_, Some(f)) => Some(PrimitiveType::Float(f)),
+            _ => None,
+        }
+    }
+
+    pub fn to_bits(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<PrimitiveType> for Value {
+    fn from(value: PrimitiveType) -> Self {
+        match value {
+            PrimitiveType::Int(n) => Value::int(n),
+            PrimitiveType::Float(f) => Value::float(f),
+        }
+    }
+}
+
+impl TryFrom<Value> for PrimitiveType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        value
+            .as_number()
+            .ok_or_else(|| anyhow!("expected a number, found {:?}", value))
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(number) = self.as_number() {
+            write!(f, "{:?}", number)
+        } else if let Some(b) = self.as_bool() {
+            write!(f, "Bool({})", b)
+        } else {
+            write!(f, "Pointer({:#x})", self.payload())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Operator;
+    use crate::testing::same_value;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_size() {
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+        assert_eq!(std::mem::size_of::<PrimitiveType>(), 16);
+    }
+
+    #[test]
+    fn test_accessors() {
+        assert_eq!(Value::int(-7).as_int(), Some(-7));
+        assert_eq!(Value::int(-7).as_float(), None);
+        assert_eq!(Value::float(1.5).as_float(), Some(1.5));
+        assert_eq!(Value::float(1.5).as_int(), None);
+        assert_eq!(Value::bool(true).as_bool(), Some(true));
+        assert_eq!(Value::bool(true).as_number(), None);
+
+        let boxed = Box::new(42);
+        let value = Value::pointer(&*boxed).unwrap();
+        assert_eq!(value.as_pointer::<i32>(), Some(&*boxed as *const i32));
+        assert_eq!(value.as_int(), None);
+        assert!(Value::pointer((1usize << 48) as *const u8).is_none());
+    }
+
+    #[test]
+    fn test_nan_keeps_its_sign() {
+        // A NaN with a payload that would otherwise read as a boxed Int.
+        let nan = f64::from_bits(QNAN | (TAG_INT << TAG_SHIFT) | 5);
+        assert!(Value::float(nan).as_float().unwrap().is_nan());
+        assert_eq!(Value::float(nan).as_int(), None);
+        let negative = Value::float(-f64::NAN).as_float().unwrap();
+        assert!(negative.is_nan() && negative.is_sign_negative());
+    }
+
+    fn primitive() -> impl Strategy<Value = PrimitiveType> {
+        prop_oneof![
+            any::<i32>().prop_map(PrimitiveType::Int),
+            any::<f64>().prop_map(PrimitiveType::Float),
+            Just(PrimitiveType::Float(-0.0)),
+            Just(PrimitiveType::Float(f64::NAN)),
+            Just(PrimitiveType::Float(-f64::NAN)),
+        ]
+    }
+
+    // Like `same_value`, but also tells NaNs apart by sign.
+    fn identical(a: PrimitiveType, b: PrimitiveType) -> bool {
+        match (a, b) {
+            (PrimitiveType::Float(a), PrimitiveType::Float(b)) if a.is_nan() => {
+                b.is_nan() && a.is_sign_negative() == b.is_sign_negative()
+            }
+            _ => same_value(a, b),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(value in primitive()) {
+            let back = PrimitiveType::try_from(Value::from(value)).unwrap();
+            prop_assert!(identical(back, value), "{:?} != {:?}", back, value);
+        }
+
+        #[test]
+        fn test_arithmetic_matches_enum(lhs in primitive(), rhs in primitive(), op in crate::testing::operator()) {
+            let expected = lhs.checked_binary(op, rhs);
+            let boxed = PrimitiveType::try_from(Value::from(lhs))
+                .unwrap()
+                .checked_binary(op, PrimitiveType::try_from(Value::from(rhs)).unwrap());
+            match (boxed, expected) {
+                (Some(boxed), Some(expected)) => {
+                    let boxed = PrimitiveType::try_from(Value::from(boxed)).unwrap();
+                    prop_assert!(identical(boxed, expected), "{:?} != {:?}", boxed, expected);
+                }
+                (boxed, expected) => prop_assert_eq!(boxed, expected),
+            }
+            if op == Operator::Minus {
+                let negated = PrimitiveType::try_from(Value::from(-lhs)).unwrap();
+                prop_assert!(identical(negated, -lhs));
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+// Static checks run before bytecode is executed, so that bytecode loaded from
+// a file cannot make the VM panic or read garbage. Every instruction must
+// decode, every constant it loads must exist, it must find enough operands on
+// the stack, and each statement's value must have been popped by the end.
+// Source map runs must be in order and start at instructions.
+//
+// There are no jumps yet, so the stack depth at each instruction follows from
+// the one before it. Jumps will need their targets checked and the depths of
+// all paths into a target compared.
+use crate::compiler::vm::bytecode::Bytecode;
+use crate::compiler::vm::opcode::OpCode;
+use anyhow::{Result, bail};
+
+#[derive(Debug)]
+pub struct Verified {
+    pub code: Vec<OpCode>,
+    // The most values the stack holds at any point.
+    pub max_stack_depth: usize,
+}
+
+pub fn verify(bytecode: &Bytecode) -> Result<Verified> {
+    // Most instructions are a single byte.
+    let mut code = Vec::with_capacity(bytecode.instructions.len());
+    let max_stack_depth = walk(bytecode, |op| code.push(op))?;
+    Ok(Verified {
+        code,
+        max_stack_depth,
+    })
+}
+
+// Like `verify`, but doesn't keep the decoded instructions, for bytecode that
+// runs only once. Returns the most values the stack holds.
+pub fn check(bytecode: &Bytecode) -> Result<usize> {
+    walk(bytecode, |_| {})
+}
+
+fn walk(bytecode: &Bytecode, mut visit: impl FnMut(OpCode)) -> Result<usize> {
+    let runs = bytecode.source_map.runs();
+    for pair in runs.windows(2) {
+        if pair[0].offset >= pair[1].offset {
+            bail!(
+                "source map run at offset {} is out of order",
+                pair[1].offset
+            );
+        }
+    }
+    let mut next_run = 0;
+    let mut depth = 0;
+    let mut max_stack_depth = 0;
+    let mut offset = 0;
+    while offset < bytecode.instructions.len() {
+        // Runs are in order, so only the next one can start here or earlier.
+        if let Some(run) = runs.get(next_run)
+            && run.offset <= offset
+        {
+            if run.offset < offset {
+                bail!(not_at_instruction(run.offset));
+            }
+            next_run += 1;
+        }
+        let (op, len) = match OpCode::decode(&bytecode.instructions[offset..]) {
+            Ok(decoded) => decoded,
+            Err(err) => return Err(err.context(format!("at offset {}", offset))),
+        };
+        if let Some(index) = op.constant_index()
+            && index as usize >= bytecode.constants.len()
+        {
+            bail!(
+                "{} at offset {} loads constant {}, but there are only {}",
+                op.name(),
+                offset,
+                index,
+                bytecode.constants.len()
+            );
+        }
+        let (pops, pushes) = op.stack_effect();
+        if depth < pops {
+            bail!(
+                "{} at offset {} pops {}, but the stack depth is {}",
+                op.name(),
+                offset,
+                pops,
+                depth
+            );
+        }
+        depth = depth - pops + pushes;
+        max_stack_depth = max_stack_depth.max(depth);
+        visit(op);
+        offset += len;
+    }
+    if depth != 0 {
+        bail!("{} values are left on the stack at the end", depth);
+    }
+    if let Some(run) = runs.get(next_run) {
+        bail!(not_at_instruction(run.offset));
+    }
+    Ok(max_stack_depth)
+}
+
+fn not_at_instruction(offset: usize) -> String {
+    format!(
+        "source map run at offset {} is not at an instruction",
+        offset
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compile;
+    use crate::compiler::vm::bytecode::Interpreter;
+    use crate::compiler::vm::source_map::SourceMap;
+    use crate::optimizer::OptLevel;
+
+    fn error(ops: &[OpCode], constants: usize) -> String {
+        let bytecode = Bytecode::from_ops(ops, vec![1.into(); constants]);
+        verify(&bytecode).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_accepts_compiled_bytecode() {
+        let bytecode = Interpreter::from_source("1 + (2 * (3 - (-4)))")
+            .unwrap()
+            .unwrap();
+        let verified = verify(&bytecode).unwrap();
+        assert_eq!(verified.code, bytecode.decode().unwrap());
+        assert_eq!(verified.max_stack_depth, 4);
+        assert_eq!(check(&bytecode).unwrap(), 4);
+        assert_eq!(verify(&Bytecode::new()).unwrap().max_stack_depth, 0);
+    }
+
+    #[test]
+    fn test_rejects_bad_bytecode() {
+        let truncated = Bytecode {
+            instructions: vec![0x01, 0x00, 0x00, 0x01, 0x00],
+            constants: vec![1.into()],
+            ..Bytecode::default()
+        };
+        let err = verify(&truncated).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "at offset 3: truncated operand for OP_CONSTANT"
+        );
+
+        assert_eq!(
+            error(&[OpCode::ConstantLong(2), OpCode::Pop], 2),
+            "OP_CONSTANT_LONG at offset 0 loads constant 2, but there are only 2"
+        );
+        assert_eq!(
+            error(&[OpCode::Constant(0), OpCode::Add, OpCode::Pop], 1),
+            "OP_ADD at offset 3 pops 2, but the stack depth is 1"
+        );
+        assert_eq!(
+            error(&[OpCode::Pop], 0),
+            "OP_POP at offset 0 pops 1, but the stack depth is 0"
+        );
+        assert_eq!(
+            error(&[OpCode::Constant(0), OpCode::Constant(0)], 1),
+            "2 values are left on the stack at the end"
+        );
+
+        let mut bytecode = Interpreter::from_source_mapped("1 + 2", OptLevel::O0).unwrap();
+        assert!(verify(&bytecode).is_ok());
+        let mut runs = bytecode.source_map.runs().to_vec();
+        let mut past_the_end = runs.clone();
+        runs[1].offset = 4;
+        bytecode.source_map = SourceMap::from_runs(runs.clone());
+        assert_eq!(
+            verify(&bytecode).unwrap_err().to_string(),
+            "source map run at offset 4 is not at an instruction"
+        );
+        past_the_end.last_mut().unwrap().offset = bytecode.instructions.len();
+        bytecode.source_map = SourceMap::from_runs(past_the_end);
+        assert_eq!(
+            verify(&bytecode).unwrap_err().to_string(),
+            format!(
+                "source map run at offset {} is not at an instruction",
+                bytecode.instructions.len()
+            )
+        );
+        runs[1].offset = 0;
+        bytecode.source_map = SourceMap::from_runs(runs);
+        assert_eq!(
+            verify(&bytecode).unwrap_err().to_string(),
+            "source map run at offset 0 is out of order"
+        );
+    }
+}
@@ -1,137 +1,576 @@
 use crate::Compile;
 use crate::ast::Node;
+use crate::compiler::limits::{Budget, Limits, ResourceExhausted};
 use crate::compiler::vm::bytecode::Bytecode;
 use crate::compiler::vm::bytecode::Interpreter as ByteCodeInterpreter;
+use crate::compiler::vm::opcode::OpCode;
+use crate::compiler::vm::source_map::SourceMap;
+#[cfg(feature = "trace")]
+use crate::compiler::vm::trace::{TraceEvent, Tracer};
+use crate::compiler::vm::verifier::{self, Verified};
+use crate::optimizer::OptLevel;
+use crate::parser;
 use crate::primitive::PrimitiveType;
 use anyhow::{Result, bail};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::time::Instant;
+
+pub use crate::compiler::runtime::RuntimeError;
 
 const STACK_SIZE: usize = 512;
 
+#[derive(Debug, Clone)]
+pub struct VmConfig {
+    // The most values the stack may hold.
+    pub stack_size: usize,
+    // The most constants the bytecode may have.
+    pub max_constants: Option<usize>,
+    // How deeply parentheses may be nested in source compiled for the VM,
+    // see `VM::from_source_with_config`.
+    pub max_depth: usize,
+    pub limits: Limits,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            stack_size: STACK_SIZE,
+            max_constants: None,
+            max_depth: parser::MAX_DEPTH,
+            limits: Limits::default(),
+        }
+    }
+}
+
+// With the nan-boxing feature, stack slots and constants are 8-byte `Value`s.
+#[cfg(feature = "nan-boxing")]
+type Slot = crate::compiler::vm::value::Value;
+#[cfg(not(feature = "nan-boxing"))]
+type Slot = PrimitiveType;
+
+#[cfg(feature = "nan-boxing")]
+fn unbox(slot: Slot) -> PrimitiveType {
+    slot.as_number().expect("the VM only stores numbers")
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+fn unbox(slot: Slot) -> PrimitiveType {
+    slot
+}
+
 pub struct VM {
     bytecode: Bytecode,
-    stack: [PrimitiveType; STACK_SIZE],
+    // `bytecode.instructions`, verified and decoded once up front.
+    code: Vec<OpCode>,
+    constants: Vec<Slot>,
+    // `stack_size` slots, of which the first `stack_ptr` are in use.
+    stack: Vec<Slot>,
     stack_ptr: usize,
-}
-
-fn usize_from_two_u8s(p1: u8, p2: u8) -> usize {
-    u16::from_be_bytes([p1, p2]) as usize
+    // Index into `code` of the next instruction to run.
+    ip: usize,
+    halted: bool,
+    // The byte offset of each instruction in `code`.
+    offsets: Vec<usize>,
+    breakpoints: BTreeSet<usize>,
+    #[cfg(feature = "trace")]
+    tracer: Option<Tracer>,
+    budget: Budget,
+    // From the verifier: how deep the program takes the stack.
+    max_stack_depth: usize,
+    // The value of each statement run so far: the ones `OP_POP` discards,
+    // then the one `OP_RETURN` returns.
+    results: Vec<PrimitiveType>,
+    // Set by `OP_RETURN`.
+    result: Option<PrimitiveType>,
+    // Set by an instruction that failed, which stops the program.
+    fault: Option<RuntimeError>,
 }
 
 impl VM {
-    pub fn new(bytecode: Bytecode) -> VM {
+    // Fails if the verifier rejects the bytecode.
+    pub fn new(bytecode: Bytecode) -> Result<VM> {
+        VM::with_config(bytecode, VmConfig::default())
+    }
+
+    pub fn with_config(bytecode: Bytecode, config: VmConfig) -> Result<VM> {
+        check_constants(&bytecode, &config)?;
+        let verified = verifier::verify(&bytecode)?;
+        Ok(Self::load(bytecode, config, verified))
+    }
+
+    // Compiles `source` with a source map, like `Compile::from_source_with`,
+    // and loads it under `config`.
+    pub fn from_source_with_config(source: &str, level: OptLevel, config: VmConfig) -> Result<VM> {
+        let bytecode =
+            ByteCodeInterpreter::from_source_mapped_with_depth(source, level, config.max_depth)?;
+        VM::with_config(bytecode, config)
+    }
+
+    // A VM for `run_once`: the bytecode is verified, but not decoded.
+    fn unloaded(bytecode: Bytecode) -> Result<VM> {
+        let config = VmConfig::default();
+        check_constants(&bytecode, &config)?;
+        let verified = Verified {
+            code: Vec::new(),
+            max_stack_depth: verifier::check(&bytecode)?,
+        };
+        Ok(Self::load(bytecode, config, verified))
+    }
+
+    fn load(bytecode: Bytecode, config: VmConfig, verified: Verified) -> VM {
+        let offsets = verified
+            .code
+            .iter()
+            .scan(0, |offset, op| {
+                let start = *offset;
+                *offset += 1 + op.operand_width();
+                Some(start)
+            })
+            .collect();
         Self {
+            halted: verified.code.is_empty(),
+            ip: 0,
+            offsets,
+            breakpoints: BTreeSet::new(),
+            #[cfg(feature = "trace")]
+            tracer: None,
+            code: verified.code,
+            max_stack_depth: verified.max_stack_depth,
+            constants: bytecode.constants.iter().map(|&c| Slot::from(c)).collect(),
             bytecode,
-            stack: unsafe { std::mem::zeroed() },
+            stack: vec![Slot::from(PrimitiveType::Int(0)); config.stack_size],
             stack_ptr: 0,
+            results: Vec::new(),
+            result: None,
+            fault: None,
+            budget: Budget::new(config.limits),
+        }
+    }
+
+    // Runs `bytecode` to completion, returning the value of the last
+    // statement. A program without statements fails with `NoResult`.
+    pub fn execute(bytecode: Bytecode) -> Result<PrimitiveType> {
+        let mut vm = VM::unloaded(bytecode)?;
+        vm.run_once()?;
+        Ok(vm.result().ok_or(RuntimeError::NoResult)?)
+    }
+
+    // Like `execute`, but returns the value of every statement.
+    pub fn execute_all(bytecode: Bytecode) -> Result<Vec<PrimitiveType>> {
+        let mut vm = VM::unloaded(bytecode)?;
+        vm.run_once()?;
+        Ok(vm.results)
+    }
+
+    // `run` for an unloaded VM. Decoding up front costs about as much as a
+    // run, so a program that runs once is decoded as it goes instead.
+    fn run_once(&mut self) -> Result<()> {
+        if self.max_stack_depth > self.stack.len() {
+            bail!(RuntimeError::StackOverflow {
+                size: self.stack.len(),
+            });
         }
+        let instructions = std::mem::take(&mut self.bytecode.instructions);
+        let mut offset = 0;
+        while offset < instructions.len() {
+            let (op, len) = OpCode::decode_verified(&instructions[offset..]);
+            if !self.dispatch(op) {
+                break;
+            }
+            offset += len;
+        }
+        self.bytecode.instructions = instructions;
+        self.halted = true;
+        self.check_fault(offset)
     }
 
+    // Runs the program from the start. The loop dispatches on the decoded
+    // `OpCode`s, so it shares its encoding with the opcode table.
+    //
+    // The verifier knows how deep the program takes the stack, so a program
+    // that would overflow fails in `reset`, and one that fits can neither
+    // overflow nor underflow: the loop skips the checks `push` and `pop` make.
     pub fn run(&mut self) -> Result<()> {
-        let mut ip = 0;
-        while ip < self.bytecode.instructions.len() {
-            let inst_addr = ip;
-            ip += 1;
-
-            match self.bytecode.instructions[inst_addr] {
-                0x01 => {
-                    // OpConst
-                    let const_idx = usize_from_two_u8s(
-                        self.bytecode.instructions[ip],
-                        self.bytecode.instructions[ip + 1],
-                    );
-                    ip += 2;
-                    self.push(self.bytecode.constants[const_idx].clone());
-                }
-                0x02 => {
-                    // OpPop
-                    self.pop();
-                }
-                0x03 => {
-                    // OpAdd
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    let value = lhs + rhs;
-                    self.push(value);
-                }
-                0x04 => {
-                    // OpSub
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    let value = lhs - rhs;
-                    self.push(value);
-                }
-                0x05 => {
-                    // OpMul
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    let value = lhs * rhs;
-                    self.push(value);
-                }
-                0x06 => {
-                    // OpDiv
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    let value = lhs / rhs;
-                    self.push(value);
-                }
-                0x0A => {
-                    // OpPlus
-                    let value = self.pop();
-                    self.push(value);
-                }
-                0x0B => {
-                    // OpMinus
-                    let value = self.pop();
-                    self.push(-value);
+        self.reset()?;
+        self.resume()
+    }
+
+    // Runs the rest of the program, e.g. after it ran out of fuel and was
+    // given more with `add_fuel`.
+    pub fn resume(&mut self) -> Result<()> {
+        if self.halted {
+            return Ok(());
+        }
+        if !self.budget.is_unlimited() {
+            return self.resume_metered();
+        }
+        let start = self.ip;
+        self.ip = self.code.len();
+        for ip in start..self.code.len() {
+            #[cfg(feature = "trace")]
+            self.trace(ip);
+            if !self.dispatch(self.code[ip]) {
+                self.ip = ip + 1;
+                break;
+            }
+        }
+        self.halted = true;
+        self.check_fault(self.offsets[self.ip - 1])
+    }
+
+    // `resume` with limits: the same loop, paying for each instruction
+    // before running it, so that one that can't be paid for runs on resume.
+    fn resume_metered(&mut self) -> Result<()> {
+        while self.ip < self.code.len() {
+            self.budget.charge()?;
+            let ip = self.ip;
+            self.ip += 1;
+            #[cfg(feature = "trace")]
+            self.trace(ip);
+            if !self.dispatch(self.code[ip]) {
+                break;
+            }
+        }
+        self.halted = true;
+        self.check_fault(self.offsets[self.ip - 1])
+    }
+
+    // Gets ready to run the program from the start, one `step` at a time.
+    pub fn reset(&mut self) -> Result<()> {
+        if self.max_stack_depth > self.stack.len() {
+            bail!(RuntimeError::StackOverflow {
+                size: self.stack.len(),
+            });
+        }
+        self.ip = 0;
+        self.halted = self.code.is_empty();
+        self.stack_ptr = 0;
+        self.results.clear();
+        self.result = None;
+        self.fault = None;
+        Ok(())
+    }
+
+    // Runs the next instruction, returning whether there are more to run.
+    // Unlike `run`, it checks the stack first, since `push` and `pop` may
+    // have been used in between.
+    pub fn step(&mut self) -> Result<bool> {
+        if self.halted {
+            return Ok(false);
+        }
+        let op = self.code[self.ip];
+        let (pops, pushes) = op.stack_effect();
+        if self.stack_ptr < pops {
+            bail!(RuntimeError::StackUnderflow);
+        }
+        if self.stack_ptr - pops + pushes > self.stack.len() {
+            bail!(RuntimeError::StackOverflow {
+                size: self.stack.len(),
+            });
+        }
+        self.budget.charge()?;
+        #[cfg(feature = "trace")]
+        self.trace(self.ip);
+        self.ip += 1;
+        self.halted = !self.dispatch(op) || self.ip == self.code.len();
+        self.check_fault(self.offsets[self.ip - 1])?;
+        Ok(!self.halted)
+    }
+
+    // Fails with the error the instruction that just ran, at `offset`,
+    // stopped with, if it did.
+    fn check_fault(&mut self, offset: usize) -> Result<()> {
+        match self.fault.take() {
+            Some(fault) => {
+                let location = self.location(offset);
+                Err(anyhow::Error::new(fault).context(format!("at {}", location)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Where the instruction at `offset` came from: its line and column if
+    // the bytecode has a source map, or else the offset.
+    pub fn location(&self, offset: usize) -> String {
+        match self.bytecode.source_map.lookup(offset) {
+            Some(span) => span.to_string(),
+            None => format!("offset {}", offset),
+        }
+    }
+
+    // Steps until the next instruction is at a breakpoint, returning whether
+    // one was hit. The first instruction always runs, so that continuing
+    // from a breakpoint gets past it.
+    pub fn run_to_breakpoint(&mut self) -> Result<bool> {
+        while self.step()? {
+            if self.breakpoints.contains(&self.ip()) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Runs one instruction, returning false if it stops the program.
+    #[inline(always)]
+    fn dispatch(&mut self, op: OpCode) -> bool {
+        match op {
+            OpCode::Constant(index) => {
+                self.push_slot(self.constants[usize::from(index)]);
+            }
+            OpCode::ConstantLong(index) => {
+                self.push_slot(self.constants[index as usize]);
+            }
+            OpCode::Pop => {
+                let value = self.pop_slot();
+                self.results.push(value);
+            }
+            OpCode::Return => {
+                let value = self.pop_slot();
+                self.results.push(value);
+                self.result = Some(value);
+                return false;
+            }
+            OpCode::Add => {
+                let rhs = self.pop_slot();
+                let lhs = self.pop_slot();
+                self.push_slot(Slot::from(lhs + rhs));
+            }
+            OpCode::Sub => {
+                let rhs = self.pop_slot();
+                let lhs = self.pop_slot();
+                self.push_slot(Slot::from(lhs - rhs));
+            }
+            OpCode::Mul => {
+                let rhs = self.pop_slot();
+                let lhs = self.pop_slot();
+                self.push_slot(Slot::from(lhs * rhs));
+            }
+            OpCode::Div => {
+                let rhs = self.pop_slot();
+                let lhs = self.pop_slot();
+                if let (PrimitiveType::Int(_), PrimitiveType::Int(0)) = (lhs, rhs) {
+                    self.fault = Some(RuntimeError::DivisionByZero);
+                    return false;
                 }
-                other => bail!("Unknown instruction {}", other),
+                self.push_slot(Slot::from(lhs / rhs));
+            }
+            OpCode::Plus => {}
+            OpCode::Minus => {
+                let value = self.pop_slot();
+                self.push_slot(Slot::from(-value));
             }
         }
+        true
+    }
+
+    #[cfg(feature = "trace")]
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    #[cfg(feature = "trace")]
+    fn trace(&mut self, ip: usize) {
+        let Some(tracer) = &mut self.tracer else {
+            return;
+        };
+        let (offset, op) = (self.offsets[ip], self.code[ip]);
+        if tracer.wants(offset, op) {
+            let stack: Vec<_> = self.stack[..self.stack_ptr]
+                .iter()
+                .map(|&slot| unbox(slot))
+                .collect();
+            tracer.record(&TraceEvent {
+                offset,
+                op,
+                stack: &stack,
+            });
+        }
+    }
+
+    // The fuel left, if it is limited. Fuel isn't refilled between runs.
+    pub fn fuel(&self) -> Option<u64> {
+        self.budget.fuel()
+    }
+
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.budget.add_fuel(fuel);
+    }
+
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.budget.set_deadline(deadline);
+    }
+
+    // Breakpoints are byte offsets of instructions, as in the disassembly.
+    pub fn add_breakpoint(&mut self, offset: usize) -> Result<()> {
+        if self.offsets.binary_search(&offset).is_err() {
+            bail!("no instruction starts at offset {}", offset);
+        }
+        self.breakpoints.insert(offset);
         Ok(())
     }
 
-    pub fn push(&mut self, node: PrimitiveType) {
-        self.stack[self.stack_ptr] = node;
+    pub fn remove_breakpoint(&mut self, offset: usize) -> bool {
+        self.breakpoints.remove(&offset)
+    }
+
+    // Breaks wherever running enters `line` of the source, see
+    // `SourceMap::line_starts`, and returns the offsets it breaks at.
+    pub fn add_line_breakpoint(&mut self, line: usize) -> Result<Vec<usize>> {
+        if self.bytecode.source_map.is_empty() {
+            bail!("the bytecode has no source map");
+        }
+        let offsets = self.bytecode.source_map.line_starts(line);
+        if offsets.is_empty() {
+            bail!("no instructions on line {}", line);
+        }
+        self.breakpoints.extend(&offsets);
+        Ok(offsets)
+    }
+
+    // Whether there were any breakpoints to remove.
+    pub fn remove_line_breakpoint(&mut self, line: usize) -> bool {
+        let offsets = self.bytecode.source_map.line_starts(line);
+        let before = self.breakpoints.len();
+        self.breakpoints.retain(|offset| !offsets.contains(offset));
+        self.breakpoints.len() < before
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    // Byte offset of the next instruction to run, or of the end of the code
+    // once the program has stopped.
+    pub fn ip(&self) -> usize {
+        match self.offsets.get(self.ip) {
+            Some(&offset) if !self.halted => offset,
+            _ => self.bytecode.instructions.len(),
+        }
+    }
+
+    // The next instruction to run, if any.
+    pub fn next_instruction(&self) -> Option<OpCode> {
+        if self.halted {
+            return None;
+        }
+        self.code.get(self.ip).copied()
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // The values on the stack, bottom first.
+    pub fn stack(&self) -> Vec<PrimitiveType> {
+        self.stack[..self.stack_ptr]
+            .iter()
+            .map(|&slot| unbox(slot))
+            .collect()
+    }
+
+    pub fn constants(&self) -> &[PrimitiveType] {
+        &self.bytecode.constants
+    }
+
+    pub fn source_map(&self) -> &SourceMap {
+        &self.bytecode.source_map
+    }
+
+    pub fn push(&mut self, node: PrimitiveType) -> Result<(), RuntimeError> {
+        if self.stack_ptr == self.stack.len() {
+            return Err(RuntimeError::StackOverflow {
+                size: self.stack.len(),
+            });
+        }
+        self.push_slot(Slot::from(node));
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<PrimitiveType, RuntimeError> {
+        if self.stack_ptr == 0 {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        Ok(self.pop_slot())
+    }
+
+    // Only for verified code, see `run`.
+    fn push_slot(&mut self, slot: Slot) {
+        self.stack[self.stack_ptr] = slot;
         self.stack_ptr += 1;
     }
 
-    pub fn pop(&mut self) -> PrimitiveType {
+    fn pop_slot(&mut self) -> PrimitiveType {
         self.stack_ptr -= 1;
-        self.stack[self.stack_ptr].clone()
+        unbox(self.stack[self.stack_ptr])
+    }
+
+    // The value `OP_RETURN` returned in the last run, if it ran one.
+    pub fn result(&self) -> Option<PrimitiveType> {
+        self.result
     }
 
-    pub fn last_popped(&self) -> &PrimitiveType {
-        // the stack pointer points to the next "free" space
-        // which also hold most recently popped element.
-        &self.stack[self.stack_ptr]
+    // The value of every statement in the last run.
+    pub fn results(&self) -> &[PrimitiveType] {
+        &self.results
+    }
+}
+
+fn check_constants(bytecode: &Bytecode, config: &VmConfig) -> Result<()> {
+    if let Some(max) = config.max_constants
+        && bytecode.constants.len() > max
+    {
+        bail!(ResourceExhausted::Constants { max });
     }
+    Ok(())
 }
 
 impl Compile for VM {
     type Output = Result<PrimitiveType>;
 
     fn from_ast(ast: Vec<Node>) -> Self::Output {
-        let mut bytecode = ByteCodeInterpreter::from_ast(ast)?;
-        let mut vm = VM::new(bytecode);
-        vm.run()?;
-        Ok(vm.last_popped().clone())
+        let bytecode = ByteCodeInterpreter::from_ast(ast)?;
+        VM::execute(bytecode)
+    }
+
+    fn from_ast_with(ast: Vec<Node>, level: OptLevel) -> Self::Output {
+        VM::execute(ByteCodeInterpreter::from_ast_with(ast, level)?)
+    }
+
+    // Compiled with a source map, so that runtime errors say where they are.
+    fn from_source(source: &str) -> Result<Self::Output> {
+        Self::from_source_with(source, OptLevel::O0)
+    }
+
+    fn from_source_with_depth(
+        source: &str,
+        level: OptLevel,
+        max_depth: usize,
+    ) -> Result<Self::Output> {
+        let (ast, spans) = parser::parse_spanned_with(source, max_depth)?;
+        Ok(ByteCodeInterpreter::from_spanned_ast(ast, spans, level).and_then(VM::execute))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ast::Operator;
+    use crate::compiler::limits::{CancelHandle, ResourceExhausted};
     use crate::compiler::vm::bytecode::Interpreter;
     use crate::primitive::PrimitiveType;
+    use crate::testing::{self, same_value};
+    use proptest::prelude::*;
+    use std::thread;
 
     #[test]
     fn test_vm() {
         let source = "1 + ((2 + 3) - (2 + 3))";
         let byte_code = Interpreter::from_source(source).unwrap().unwrap();
         println!("{:?}", byte_code);
-        let mut vm = VM::new(byte_code);
-        vm.run();
-        assert_eq!(*vm.last_popped(), 1.into());
+        let mut vm = VM::new(byte_code).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.result(), Some(1.into()));
     }
 
     #[test]
@@ -139,9 +578,341 @@ mod tests {
         let source = "1 + ((2 * 3) - (6 / 3))";
         let byte_code = Interpreter::from_source(source).unwrap().unwrap();
         println!("{:?}", byte_code);
-        let mut vm = VM::new(byte_code);
-        vm.run();
-        assert_eq!(*vm.last_popped(), 5.into());
+        let mut vm = VM::new(byte_code).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.result(), Some(5.into()));
+    }
+
+    #[test]
+    fn test_long_constant_index() {
+        let mut interpreter = Interpreter::new();
+        for value in 0..=65536 {
+            interpreter.add_constant_instruction(value.into());
+            interpreter.add_instruction(OpCode::Pop);
+        }
+        let results = VM::execute_all(interpreter.bytecode).unwrap();
+        assert_eq!(results.len(), 65537);
+        assert_eq!(results[65536], 65536.into());
+    }
+
+    #[test]
+    fn test_rejects_invalid_bytecode() {
+        let bytecode = Bytecode {
+            instructions: vec![0x01, 0x00],
+            ..Bytecode::default()
+        };
+        assert!(VM::new(bytecode).is_err());
+        let unbalanced = Bytecode::from_ops(
+            &[OpCode::Constant(0), OpCode::Add, OpCode::Pop],
+            vec![1.into()],
+        );
+        assert!(VM::new(unbalanced).is_err());
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // 1 + (1 + (1 + ...)) keeps every operand on the stack.
+        let depth = STACK_SIZE + 1;
+        let mut ops = vec![OpCode::Constant(0); depth];
+        ops.extend(vec![OpCode::Add; depth - 1]);
+        ops.push(OpCode::Return);
+        let bytecode = Bytecode::from_ops(&ops, vec![1.into()]);
+
+        let err = VM::execute(bytecode.clone()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::StackOverflow { size: STACK_SIZE })
+        );
+        let config = VmConfig {
+            stack_size: depth,
+            ..VmConfig::default()
+        };
+        let mut vm = VM::with_config(bytecode, config).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.result(), Some((depth as i32).into()));
+    }
+
+    #[test]
+    fn test_max_constants() {
+        let bytecode = Interpreter::from_source("1 + 2 + 3").unwrap().unwrap();
+        let config = |max| VmConfig {
+            max_constants: Some(max),
+            ..VmConfig::default()
+        };
+        assert!(VM::with_config(bytecode.clone(), config(3)).is_ok());
+        let err = VM::with_config(bytecode, config(2)).err().unwrap();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&ResourceExhausted::Constants { max: 2 })
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let config = VmConfig {
+            max_depth: 1,
+            ..VmConfig::default()
+        };
+        let vm = VM::from_source_with_config("(1) + (2)", OptLevel::O0, config.clone());
+        assert!(vm.is_ok());
+        let err = VM::from_source_with_config("((1))", OptLevel::O0, config)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&ResourceExhausted::Depth { max: 1 })
+        );
+        let err = VM::from_source_with_depth("((1))", OptLevel::O0, 1).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&ResourceExhausted::Depth { max: 1 })
+        );
+    }
+
+    #[test]
+    fn test_long_chain() {
+        // More distinct constants than `OP_CONSTANT` can index.
+        let terms = 100_000;
+        let mut source = String::from("0");
+        for i in 1..terms {
+            source.push_str(&format!(" + {}", i));
+        }
+        let sum = (0..terms).fold(0i32, |sum, i| sum.wrapping_add(i));
+        for level in [OptLevel::O0, OptLevel::O2] {
+            let value = VM::from_source_with(&source, level).unwrap().unwrap();
+            assert_eq!(value, sum.into());
+        }
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut vm = VM::new(Bytecode::new()).unwrap();
+        assert_eq!(vm.pop(), Err(RuntimeError::StackUnderflow));
+        vm.push(2.into()).unwrap();
+        assert_eq!(vm.pop(), Ok(2.into()));
+    }
+
+    #[test]
+    fn test_statement_results() {
+        let ast = vec![Node::Int(1), Node::Float(2.5), Node::Int(3)];
+        let bytecode = Interpreter::from_ast(ast).unwrap();
+        let mut vm = VM::new(bytecode.clone()).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.results(), [1.into(), 2.5.into(), 3.into()]);
+        assert_eq!(vm.result(), Some(3.into()));
+        assert_eq!(VM::execute(bytecode).unwrap(), 3.into());
+
+        // Nothing after `OP_RETURN` runs.
+        let bytecode = Bytecode::from_ops(
+            &[
+                OpCode::Constant(0),
+                OpCode::Return,
+                OpCode::Constant(1),
+                OpCode::Pop,
+            ],
+            vec![1.into(), 2.into()],
+        );
+        assert_eq!(VM::execute_all(bytecode).unwrap(), [1.into()]);
+    }
+
+    #[test]
+    fn test_empty_program() {
+        let bytecode = Interpreter::from_ast(vec![]).unwrap();
+        assert!(bytecode.instructions.is_empty());
+        let mut vm = VM::new(bytecode.clone()).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.result(), None);
+        assert!(vm.results().is_empty());
+        let err = VM::execute(bytecode).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&RuntimeError::NoResult));
+        assert!(VM::from_ast(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_step() {
+        // 0000 OP_CONSTANT 0, 0003 OP_CONSTANT 1, 0006 OP_MUL, 0007 OP_RETURN
+        let bytecode = Interpreter::from_source("2 * 3").unwrap().unwrap();
+        let mut vm = VM::new(bytecode).unwrap();
+        assert_eq!(vm.ip(), 0);
+        assert_eq!(vm.next_instruction(), Some(OpCode::Constant(0)));
+        assert!(vm.step().unwrap());
+        assert!(vm.step().unwrap());
+        assert_eq!(vm.stack(), [2.into(), 3.into()]);
+        assert_eq!(vm.ip(), 6);
+        assert!(vm.step().unwrap());
+        assert_eq!(vm.stack(), [6.into()]);
+        assert!(!vm.step().unwrap());
+        assert!(vm.is_halted());
+        assert_eq!(vm.next_instruction(), None);
+        assert_eq!(vm.ip(), 8);
+        assert_eq!(vm.result(), Some(6.into()));
+        assert!(!vm.step().unwrap());
+
+        vm.reset().unwrap();
+        assert_eq!(vm.ip(), 0);
+        assert_eq!(vm.result(), None);
+        assert_eq!(vm.constants(), [2.into(), 3.into()]);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let bytecode = Interpreter::from_source("1 + 2 + 3").unwrap().unwrap();
+        // 0000 OP_CONSTANT 0, 0003 OP_CONSTANT 1, 0006 OP_ADD,
+        // 0007 OP_CONSTANT 2, 0010 OP_ADD, 0011 OP_RETURN
+        let mut vm = VM::new(bytecode).unwrap();
+        assert!(vm.add_breakpoint(4).is_err());
+        vm.add_breakpoint(0).unwrap();
+        vm.add_breakpoint(6).unwrap();
+        vm.add_breakpoint(10).unwrap();
+        assert_eq!(vm.breakpoints().collect::<Vec<_>>(), [0, 6, 10]);
+
+        // The breakpoint at the current instruction is passed over.
+        assert!(vm.run_to_breakpoint().unwrap());
+        assert_eq!(vm.ip(), 6);
+        assert_eq!(vm.stack(), [1.into(), 2.into()]);
+        assert!(vm.remove_breakpoint(10));
+        assert!(!vm.run_to_breakpoint().unwrap());
+        assert_eq!(vm.result(), Some(6.into()));
+    }
+
+    #[test]
+    fn test_line_breakpoints() {
+        let source = "1 +\n  (2 * 3)\n  - 4";
+        // 0000 OP_CONSTANT 0               ; 1:1, 0003 OP_CONSTANT 1               ; 2:4,
+        // 0006 OP_CONSTANT 2               ; 2:8, 0009 OP_MUL                      ; 2:4, 0010 OP_ADD                      ; 1:1,
+        // 0011 OP_CONSTANT 3               ; 3:5, 0014 OP_SUB                      ; 1:1, 0015 OP_RETURN
+        let bytecode = Interpreter::from_source_mapped(source, OptLevel::O0).unwrap();
+        let mut vm = VM::new(bytecode).unwrap();
+        assert_eq!(vm.add_line_breakpoint(2).unwrap(), [3]);
+        assert_eq!(vm.add_line_breakpoint(1).unwrap(), [0, 10, 14]);
+        assert!(vm.add_line_breakpoint(4).is_err());
+
+        assert!(vm.run_to_breakpoint().unwrap());
+        assert_eq!(vm.ip(), 3);
+        assert!(vm.run_to_breakpoint().unwrap());
+        assert_eq!(vm.ip(), 10);
+        assert_eq!(vm.stack(), [1.into(), 6.into()]);
+        assert!(vm.remove_line_breakpoint(1));
+        assert!(!vm.remove_line_breakpoint(1));
+        assert!(!vm.run_to_breakpoint().unwrap());
+        assert_eq!(vm.result(), Some(3.into()));
+
+        let unmapped = Interpreter::from_source(source).unwrap().unwrap();
+        let err = VM::new(unmapped)
+            .unwrap()
+            .add_line_breakpoint(1)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "the bytecode has no source map");
+    }
+
+    #[test]
+    fn test_step_checks_the_stack() {
+        let bytecode = Interpreter::from_source("1 + 2").unwrap().unwrap();
+        let config = VmConfig {
+            stack_size: 2,
+            ..VmConfig::default()
+        };
+        let mut vm = VM::with_config(bytecode, config).unwrap();
+        vm.push(0.into()).unwrap();
+        vm.step().unwrap();
+        let err = vm.step().unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&RuntimeError::StackOverflow { size: 2 })
+        );
+    }
+
+    #[test]
+    fn test_fuel() {
+        // 0000 OP_CONSTANT 0, 0003 OP_CONSTANT 1, 0006 OP_ADD,
+        // 0007 OP_CONSTANT 2, 0010 OP_ADD, 0011 OP_RETURN
+        let bytecode = Interpreter::from_source("1 + 2 + 3").unwrap().unwrap();
+        let config = VmConfig {
+            limits: Limits {
+                fuel: Some(4),
+                ..Limits::default()
+            },
+            ..VmConfig::default()
+        };
+        let mut vm = VM::with_config(bytecode, config).unwrap();
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ResourceExhausted::Fuel));
+        assert_eq!(vm.ip(), 10);
+        assert_eq!(vm.stack(), [3.into(), 3.into()]);
+        assert!(vm.step().is_err());
+
+        vm.add_fuel(2);
+        vm.resume().unwrap();
+        assert_eq!(vm.result(), Some(6.into()));
+        assert_eq!(vm.fuel(), Some(0));
+        assert!(vm.run().is_err());
+    }
+
+    #[test]
+    fn test_deadline_and_cancel() {
+        let bytecode = Interpreter::from_source("1 + 2").unwrap().unwrap();
+        let config = VmConfig {
+            limits: Limits {
+                deadline: Some(Instant::now()),
+                ..Limits::default()
+            },
+            ..VmConfig::default()
+        };
+        let mut vm = VM::with_config(bytecode.clone(), config).unwrap();
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ResourceExhausted::Deadline));
+        vm.set_deadline(None);
+        vm.run().unwrap();
+        assert_eq!(vm.result(), Some(3.into()));
+
+        let cancel = CancelHandle::new();
+        let config = VmConfig {
+            limits: Limits {
+                cancel: Some(cancel.clone()),
+                ..Limits::default()
+            },
+            ..VmConfig::default()
+        };
+        let mut vm = VM::with_config(bytecode, config).unwrap();
+        thread::spawn(move || cancel.cancel()).join().unwrap();
+        let err = vm.run().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ResourceExhausted::Cancelled));
+    }
+
+    #[test]
+    fn test_run_twice() {
+        let bytecode = Interpreter::from_source("2 * 3").unwrap().unwrap();
+        let mut vm = VM::new(bytecode).unwrap();
+        vm.run().unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.result(), Some(6.into()));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let err = VM::from_source("1 +\n  (2 / 0)").unwrap().unwrap_err();
+        assert_eq!(format!("{:#}", err), "at 2:4: division by zero");
+        assert_eq!(err.downcast_ref(), Some(&RuntimeError::DivisionByZero));
+
+        // Without a source map, the instruction's offset.
+        let bytecode = Interpreter::from_source("4 / 0").unwrap().unwrap();
+        let mut vm = VM::new(bytecode).unwrap();
+        assert!(vm.step().unwrap());
+        assert!(vm.step().unwrap());
+        let err = vm.step().unwrap_err();
+        assert_eq!(format!("{:#}", err), "at offset 6: division by zero");
+        assert!(vm.is_halted());
+        let result = VM::from_source("4.0 / 0").unwrap().unwrap();
+        assert_eq!(result, f64::INFINITY.into());
+    }
+
+    #[test]
+    fn test_invalid_unary() {
+        let err = VM::from_source("(/2) + 1").unwrap().unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&RuntimeError::InvalidUnary(Operator::Divide))
+        );
     }
 
     #[test]
@@ -149,8 +920,26 @@ mod tests {
         let source = "1.2 + 3.6";
         let byte_code = Interpreter::from_source(source).unwrap().unwrap();
         println!("{:?}", byte_code);
-        let mut vm = VM::new(byte_code);
-        vm.run();
-        assert_eq!(*vm.last_popped(), 4.8.into());
+        let mut vm = VM::new(byte_code).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.result(), Some(4.8.into()));
+    }
+
+    // Runs under both value representations, against the enum-based
+    // reference evaluation.
+    proptest! {
+        #[test]
+        fn test_matches_reference(node in testing::program()) {
+            // Skip programs that fail at runtime.
+            prop_assume!(testing::eval(&node).is_some());
+            let expected = testing::eval(&node).unwrap();
+            let bytecode = Interpreter::from_ast(vec![node]).unwrap();
+            // `execute` dispatches on the bytes, `run` on decoded instructions.
+            let mut vm = VM::new(bytecode.clone()).unwrap();
+            vm.run().unwrap();
+            for result in [VM::execute(bytecode).unwrap(), vm.result().unwrap()] {
+                prop_assert!(same_value(result, expected), "{:?} != {:?}", result, expected);
+            }
+        }
     }
 }
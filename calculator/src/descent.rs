@@ -0,0 +1,334 @@
+// Hand-written recursive-descent parser producing the same `Node`s as the
+// pest grammar in `grammar.pest`, without building an intermediate parse tree.
+use crate::ast::{Node, Operator, Span, Spans, parse_float_literal, parse_int_literal};
+use crate::compiler::limits::ResourceExhausted;
+use crate::lexer::{Lexer, Token, TokenKind};
+use crate::parser::MAX_DEPTH;
+use anyhow::{Result, bail};
+
+pub fn parse(source: &str) -> Result<Vec<Node>> {
+    parse_with(source, MAX_DEPTH)
+}
+
+pub fn parse_with(source: &str, max_depth: usize) -> Result<Vec<Node>> {
+    Ok(parse_spanned_with(source, max_depth)?.0)
+}
+
+// Also returns the span of every node, in post-order, as `parser` does.
+pub fn parse_spanned_with(source: &str, max_depth: usize) -> Result<(Vec<Node>, Vec<Span>)> {
+    let mut parser = Parser::new(source, max_depth)?;
+    let node = parser.parse_expr()?;
+    // Program = SOI ~ Expr ~ (EOI | ";"); input after the `;` is ignored.
+    match parser.current.kind {
+        TokenKind::Eof | TokenKind::Semicolon => Ok((vec![node], parser.spans.into_vec())),
+        _ => Err(parser.unexpected("end of input or ';'")),
+    }
+}
+
+struct Parser<'a> {
+    lexer: Lexer<'a>,
+    current: Token<'a>,
+    // How many parentheses are open.
+    depth: usize,
+    max_depth: usize,
+    // Where the operand parsed last ends, including the `)` around it.
+    term_end: usize,
+    spans: Spans,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str, max_depth: usize) -> Result<Self> {
+        let mut lexer = Lexer::new(source);
+        let current = lexer.next_token()?;
+        Ok(Self {
+            lexer,
+            current,
+            depth: 0,
+            max_depth,
+            term_end: 0,
+            spans: Spans::new(source),
+        })
+    }
+
+    fn advance(&mut self) -> Result<Token<'a>> {
+        let next = self.lexer.next_token()?;
+        Ok(std::mem::replace(&mut self.current, next))
+    }
+
+    // Expr = (UnaryExpr | Term) ~ (Operator ~ Term)*
+    // All binary operators share one precedence level and associate to the left.
+    fn parse_expr(&mut self) -> Result<Node> {
+        // From the `(` of a parenthesized first operand.
+        let start = self.current.offset;
+        let mut lhs = match self.current.kind {
+            TokenKind::Operator(op) => {
+                let start = self.advance()?.offset;
+                self.parse_unary(op, start)?
+            }
+            _ => self.parse_term()?,
+        };
+        while let TokenKind::Operator(op) = self.current.kind {
+            self.advance()?;
+            let rhs = self.parse_term()?;
+            self.spans.push(start, self.term_end);
+            lhs = Node::BinaryExpr {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self, op: Operator, start: usize) -> Result<Node> {
+        let child = Box::new(self.parse_term()?);
+        self.spans.push(start, self.term_end);
+        Ok(Node::UnaryExpr { op, child })
+    }
+
+    // Term = Float | Int | "(" ~ Expr ~ ")"
+    fn parse_term(&mut self) -> Result<Node> {
+        match self.current.kind {
+            TokenKind::Int => {
+                let token = self.advance()?;
+                self.term_end = token.offset + token.text.len();
+                self.spans.push(token.offset, self.term_end);
+                Ok(Node::Int(parse_int_literal(token.text, false)?))
+            }
+            TokenKind::Float => {
+                let token = self.advance()?;
+                self.term_end = token.offset + token.text.len();
+                self.spans.push(token.offset, self.term_end);
+                Ok(Node::Float(parse_float_literal(token.text, false)?))
+            }
+            TokenKind::LParen => {
+                if self.depth == self.max_depth {
+                    bail!(ResourceExhausted::Depth {
+                        max: self.max_depth
+                    });
+                }
+                self.advance()?;
+                if let Some(literal) = self.parse_negative_literal()? {
+                    return Ok(literal);
+                }
+                self.depth += 1;
+                let expr = self.parse_expr()?;
+                self.depth -= 1;
+                if self.current.kind != TokenKind::RParen {
+                    return Err(self.unexpected("')'"));
+                }
+                self.term_end = self.advance()?.offset + 1;
+                Ok(expr)
+            }
+            _ => Err(self.unexpected("a number or '('")),
+        }
+    }
+
+    // After a `(`: reads `-5)` as a negative literal rather than a unary
+    // minus, as `parser` does, or leaves the input alone.
+    fn parse_negative_literal(&mut self) -> Result<Option<Node>> {
+        if self.current.kind != TokenKind::Operator(Operator::Minus) {
+            return Ok(None);
+        }
+        let mut lookahead = self.lexer.clone();
+        let (Ok(digits), Ok(rparen)) = (lookahead.next_token(), lookahead.next_token()) else {
+            return Ok(None);
+        };
+        if rparen.kind != TokenKind::RParen {
+            return Ok(None);
+        }
+        let node = match digits.kind {
+            TokenKind::Int => Node::Int(parse_int_literal(digits.text, true)?),
+            TokenKind::Float => Node::Float(parse_float_literal(digits.text, true)?),
+            _ => return Ok(None),
+        };
+        let start = self.current.offset;
+        self.spans.push(start, digits.offset + digits.text.len());
+        self.term_end = rparen.offset + 1;
+        self.lexer = lookahead;
+        self.advance()?;
+        Ok(Some(node))
+    }
+
+    fn unexpected(&self, expected: &str) -> anyhow::Error {
+        let found = match self.current.kind {
+            TokenKind::Eof => "end of input".to_string(),
+            _ => format!("{:?}", self.current.text),
+        };
+        anyhow::anyhow!(
+            "expected {} at {}, found {}",
+            expected,
+            self.lexer.location(self.current.offset),
+            found
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_pest_spanned_with;
+    use proptest::prelude::*;
+
+    // Spans included.
+    fn assert_equivalent(source: &str) {
+        let pest = parse_pest_spanned_with(source, MAX_DEPTH);
+        match (pest, parse_spanned_with(source, MAX_DEPTH)) {
+            (Ok(expected), Ok(actual)) => assert_eq!(expected, actual, "source: {:?}", source),
+            (Err(_), Err(_)) => {}
+            (expected, actual) => panic!(
+                "parsers disagree on {:?}: pest {:?}, descent {:?}",
+                source, expected, actual
+            ),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("-11 + 2").unwrap(),
+            vec![Node::BinaryExpr {
+                lhs: Box::new(Node::UnaryExpr {
+                    op: Operator::Minus,
+                    child: Box::new(Node::Int(11))
+                }),
+                op: Operator::Plus,
+                rhs: Box::new(Node::Int(2))
+            }]
+        );
+    }
+
+    #[test]
+    fn test_error_message() {
+        assert_eq!(
+            parse("1 + )").unwrap_err().to_string(),
+            "expected a number or '(' at 1:5, found \")\""
+        );
+        assert_eq!(
+            parse("(1").unwrap_err().to_string(),
+            "expected ')' at 1:3, found end of input"
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(parse(&nested(MAX_DEPTH)).unwrap(), vec![Node::Int(1)]);
+        let err = parse(&nested(MAX_DEPTH + 1)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&ResourceExhausted::Depth { max: MAX_DEPTH })
+        );
+        assert!(parse_with("-(1) * ((2))", 1).is_err());
+        assert_equivalent(&nested(MAX_DEPTH + 1));
+    }
+
+    #[test]
+    fn test_long_chain() {
+        let chain = |operators| format!("1{}", " + 1".repeat(operators));
+        let ast = parse_with(&chain(200_000), 1).unwrap();
+        assert_eq!(ast[0].size(), 400_001);
+        assert_equivalent(&chain(200_000));
+        assert_equivalent(&format!("-({})", chain(MAX_DEPTH)));
+        assert_equivalent("1 + (2 * 3 + 4)");
+    }
+
+    #[test]
+    fn test_equivalence_corpus() {
+        let corpus = [
+            include_str!("../examples/sample.calc"),
+            "1",
+            "1.5",
+            "-1",
+            "+1",
+            "*1",
+            "2 + 2 * 3",
+            "1 + ((2 + 3) - (2 + 3))",
+            "1 + ((2 * 3) - (6 / 3))",
+            "-(1 + 2) * 3",
+            "(-1)",
+            "((((7))))",
+            " \t\r\n1\n+\n2\n",
+            "1 + 2; this is ignored $",
+            "1;2",
+            "1 ;",
+            "// leading\n1 + // inside\n2 // trailing",
+            "1 //2",
+            "1 ///2",
+            "1 +//c\n2",
+            "// only a comment",
+            "1 / /2",
+            "(-5) - ( -1.5 )",
+            "-(-2147483648)",
+            "(-(5))",
+            "(1 + 2) * (3 // )\n) - 4",
+            "1 * ( -2 ) + ((3))",
+            "((-5))",
+            "(-5 + 1)",
+            "(+5)",
+            // rejected by both
+            "",
+            ";",
+            "1 +",
+            "1 2",
+            "--1",
+            "1 - -2",
+            "1.",
+            ".5",
+            "1.2.3",
+            "(1",
+            "1)",
+            "()",
+            "1 $ 2",
+            "99999999999",
+            "2147483648",
+            "(-2147483649)",
+            "-2147483648",
+            "(-5",
+            "~5",
+            "inf",
+            "nan",
+        ];
+        for source in corpus {
+            assert_equivalent(source);
+        }
+    }
+
+    fn token() -> impl Strategy<Value = &'static str> {
+        prop::sample::select(vec![
+            "0", "1", "42", "3.25", "0.5", "+", "-", "*", "/", "(", ")", " ", "\n", ";", ".", "//",
+        ])
+    }
+
+    fn expression() -> impl Strategy<Value = String> {
+        let leaf = prop_oneof![
+            any::<u16>().prop_map(|n| n.to_string()),
+            (any::<u16>(), 0..1000u16).prop_map(|(i, f)| format!("{}.{}", i, f)),
+        ];
+        leaf.prop_recursive(8, 64, 4, |inner| {
+            let op = prop::sample::select(vec!["+", "-", "*", "/"]);
+            prop_oneof![
+                inner.clone().prop_map(|e| format!("({})", e)),
+                (op.clone(), inner.clone()).prop_map(|(op, e)| format!("{}{}", op, e)),
+                (inner.clone(), prop::collection::vec((op, inner), 1..4)).prop_map(
+                    |(first, rest)| {
+                        rest.into_iter()
+                            .fold(first, |acc, (op, e)| format!("{} {} {}", acc, op, e))
+                    }
+                ),
+            ]
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_equivalence_random_tokens(tokens in prop::collection::vec(token(), 0..24)) {
+            assert_equivalent(&tokens.concat());
+        }
+
+        #[test]
+        fn test_equivalence_random_expressions(source in expression()) {
+            assert_equivalent(&source);
+        }
+    }
+}
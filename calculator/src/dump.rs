@@ -0,0 +1,211 @@
+// JSON and S-expression dumps of parsed programs, for tools that want to
+// consume or produce gkl programs without linking the parser.
+use crate::ast::{Node, Operator};
+use crate::compiler::limits::ResourceExhausted;
+use crate::parser::MAX_DEPTH;
+use crate::sexpr::{self, Sexpr};
+use anyhow::{Result, anyhow, bail};
+
+// Both formats nest once per level of nodes, and writing or reading them
+// recurses as deep, so trees nested more than `MAX_DEPTH` deep are refused,
+// like source with parentheses nested deeper.
+fn check_depth(nodes: &[Node]) -> Result<()> {
+    let mut stack: Vec<(&Node, usize)> = nodes.iter().map(|node| (node, 0)).collect();
+    while let Some((node, depth)) = stack.pop() {
+        if depth > MAX_DEPTH {
+            bail!(ResourceExhausted::Depth { max: MAX_DEPTH });
+        }
+        match node {
+            Node::Int(_) | Node::Float(_) => {}
+            Node::UnaryExpr { child, .. } => stack.push((child, depth + 1)),
+            Node::BinaryExpr { lhs, rhs, .. } => {
+                stack.push((lhs, depth + 1));
+                stack.push((rhs, depth + 1));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn ast_to_json(nodes: &[Node]) -> Result<String> {
+    check_depth(nodes)?;
+    Ok(serde_json::to_string_pretty(nodes)?)
+}
+
+pub fn ast_from_json(json: &str) -> Result<Vec<Node>> {
+    Ok(serde_json::from_str(json)?)
+}
+
+// One expression per statement, e.g. `(+ (- 11) 2)` for `-11 + 2`.
+pub fn ast_to_sexpr(nodes: &[Node]) -> Result<String> {
+    check_depth(nodes)?;
+    Ok(nodes
+        .iter()
+        .map(|node| format!("{}\n", node_to_sexpr(node)))
+        .collect())
+}
+
+pub fn ast_from_sexpr(source: &str) -> Result<Vec<Node>> {
+    sexpr::parse_all(source)?
+        .iter()
+        .map(node_from_sexpr)
+        .collect()
+}
+
+fn node_to_sexpr(node: &Node) -> Sexpr {
+    match node {
+        Node::Int(n) => Sexpr::atom(n.to_string()),
+        Node::Float(f) => Sexpr::atom(float_atom(*f)),
+        Node::UnaryExpr { op, child } => {
+            Sexpr::List(vec![Sexpr::atom(op.to_string()), node_to_sexpr(child)])
+        }
+        Node::BinaryExpr { op, lhs, rhs } => Sexpr::List(vec![
+            Sexpr::atom(op.to_string()),
+            node_to_sexpr(lhs),
+            node_to_sexpr(rhs),
+        ]),
+    }
+}
+
+fn node_from_sexpr(sexpr: &Sexpr) -> Result<Node> {
+    let items = match sexpr {
+        Sexpr::Atom(text) => return literal_from_atom(text),
+        Sexpr::List(items) => items,
+    };
+    let Some((head, operands)) = items.split_first() else {
+        bail!("empty expression ()");
+    };
+    let op = match head.as_atom()? {
+        "+" => Operator::Plus,
+        "-" => Operator::Minus,
+        "*" => Operator::Multiply,
+        "/" => Operator::Divide,
+        other => bail!("unknown operator {:?} in {}", other, sexpr),
+    };
+    match operands {
+        [child] => Ok(Node::UnaryExpr {
+            op,
+            child: Box::new(node_from_sexpr(child)?),
+        }),
+        [lhs, rhs] => Ok(Node::BinaryExpr {
+            op,
+            lhs: Box::new(node_from_sexpr(lhs)?),
+            rhs: Box::new(node_from_sexpr(rhs)?),
+        }),
+        _ => bail!("expected one or two operands in {}", sexpr),
+    }
+}
+
+// Floats always carry a `.` (or are `inf`/`nan`), so their type survives.
+pub(crate) fn float_atom(f: f64) -> String {
+    if f.is_nan() {
+        let sign = if f.is_sign_negative() { "-" } else { "" };
+        format!("{}nan", sign)
+    } else if f.is_infinite() || f.fract() != 0.0 {
+        f.to_string()
+    } else {
+        format!("{}.0", f)
+    }
+}
+
+pub(crate) fn literal_from_atom(text: &str) -> Result<Node> {
+    let is_float = text.contains('.') || text.ends_with("inf") || text.ends_with("nan");
+    if is_float {
+        text.parse()
+            .map(Node::Float)
+            .map_err(|_| anyhow!("invalid float {:?}", text))
+    } else {
+        text.parse()
+            .map(Node::Int)
+            .map_err(|_| anyhow!("invalid integer {:?}", text))
+    }
+}
+
+// JSON has no spelling for non-finite numbers, so those become strings.
+pub(crate) mod json_float {
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        if value.is_finite() {
+            serializer.serialize_f64(*value)
+        } else {
+            serializer.serialize_str(&super::float_atom(*value))
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Number(f64),
+            Text(String),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Number(value) => Ok(value),
+            Repr::Text(text) => match text.as_str() {
+                "inf" | "-inf" | "nan" | "-nan" => Ok(text.parse().unwrap()),
+                _ => Err(de::Error::custom(format!("invalid float {:?}", text))),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_ast_sexpr() {
+        let ast = parse("-11 + 2").unwrap();
+        assert_eq!(ast_to_sexpr(&ast).unwrap(), "(+ (- 11) 2)\n");
+        assert_eq!(ast_from_sexpr("(+ (- 11) 2)").unwrap(), ast);
+
+        let inf = Node::Float(f64::INFINITY).lossless().to_string();
+        let ast = parse(&format!("(-5) * 2.0 / {}", inf)).unwrap();
+        assert_eq!(ast_to_sexpr(&ast).unwrap(), "(/ (* -5 2.0) inf)\n");
+        assert_eq!(ast_from_sexpr(&ast_to_sexpr(&ast).unwrap()).unwrap(), ast);
+    }
+
+    #[test]
+    fn test_ast_sexpr_errors() {
+        assert!(ast_from_sexpr("(% 1 2)").is_err());
+        assert!(ast_from_sexpr("(+ 1 2 3)").is_err());
+        assert!(ast_from_sexpr("()").is_err());
+        assert!(ast_from_sexpr("1.2.3").is_err());
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let chain = |operators| parse(&format!("1{}", " + 1".repeat(operators))).unwrap();
+        let sexpr = ast_to_sexpr(&chain(MAX_DEPTH)).unwrap();
+        assert_eq!(ast_from_sexpr(&sexpr).unwrap(), chain(MAX_DEPTH));
+        let depth = Some(&ResourceExhausted::Depth { max: MAX_DEPTH });
+        let err = ast_from_sexpr(&format!("(- {})", sexpr)).unwrap_err();
+        assert_eq!(err.downcast_ref(), depth);
+        let err = ast_to_sexpr(&chain(MAX_DEPTH + 1)).unwrap_err();
+        assert_eq!(err.downcast_ref(), depth);
+        let err = ast_to_json(&chain(MAX_DEPTH + 1)).unwrap_err();
+        assert_eq!(err.downcast_ref(), depth);
+    }
+
+    #[test]
+    fn test_ast_json() {
+        let ast = parse("-11 + 2.5").unwrap();
+        assert_eq!(
+            serde_json::to_string(&ast).unwrap(),
+            r#"[{"BinaryExpr":{"op":"+","lhs":{"UnaryExpr":{"op":"-","child":{"Int":11}}},"rhs":{"Float":2.5}}}]"#
+        );
+        assert_eq!(ast_from_json(&ast_to_json(&ast).unwrap()).unwrap(), ast);
+    }
+
+    #[test]
+    fn test_ast_json_non_finite() {
+        let ast = vec![Node::Float(f64::NEG_INFINITY), Node::Float(-0.0)];
+        let json = ast_to_json(&ast).unwrap();
+        assert!(json.contains("\"-inf\""));
+        let back = ast_from_json(&json).unwrap();
+        assert_eq!(back, ast);
+        assert!(matches!(back[1], Node::Float(f) if f.is_sign_negative()));
+    }
+}
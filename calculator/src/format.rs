@@ -0,0 +1,237 @@
+// Canonical source formatter: single spaces around binary operators, only the
+// parentheses the grammar needs, and long expressions broken before operators.
+use crate::ast::{Node, Position};
+use crate::lexer::{Lexer, TokenKind};
+use crate::parser;
+use anyhow::{Result, bail};
+
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    pub max_width: usize,
+    pub indent_width: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            max_width: 80,
+            indent_width: 4,
+        }
+    }
+}
+
+pub fn format_source(source: &str) -> Result<String> {
+    format_source_with(source, &FormatOptions::default())
+}
+
+pub fn format_source_with(source: &str, options: &FormatOptions) -> Result<String> {
+    let ast = parser::parse(source)?;
+    let comments = Comments::collect(source)?;
+    let layout = Layout { options };
+
+    let mut out = String::new();
+    // The AST does not record where comments sit inside an expression, so
+    // those are moved above the statement.
+    for comment in comments.leading.iter().chain(&comments.inner) {
+        out.push_str(comment);
+        out.push('\n');
+    }
+    for node in &ast {
+        out.push_str(&layout.expr(node, Position::Top, 0, 0));
+        out.push(';');
+    }
+    let mut trailing = comments.trailing.iter();
+    if comments.trailing_on_same_line
+        && let Some(comment) = trailing.next()
+    {
+        out.push(' ');
+        out.push_str(comment);
+    }
+    out.push('\n');
+    for comment in trailing {
+        out.push_str(comment);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[derive(Default)]
+struct Comments<'a> {
+    leading: Vec<&'a str>,
+    inner: Vec<&'a str>,
+    trailing: Vec<&'a str>,
+    trailing_on_same_line: bool,
+}
+
+impl<'a> Comments<'a> {
+    fn collect(source: &'a str) -> Result<Self> {
+        let mut lexer = Lexer::with_comments(source);
+        let mut comments = Comments::default();
+        let mut code_end = None;
+        let mut pending = Vec::new();
+        let mut terminated = false;
+        loop {
+            let token = lexer.next_token()?;
+            match token.kind {
+                TokenKind::Comment => {
+                    if code_end.is_none() {
+                        comments.leading.push(token.text);
+                    } else {
+                        pending.push(token);
+                    }
+                }
+                TokenKind::Eof => break,
+                _ if terminated => {
+                    bail!(
+                        "unexpected input after ';' at {}",
+                        lexer.location(token.offset)
+                    )
+                }
+                kind => {
+                    comments.inner.extend(pending.drain(..).map(|c| c.text));
+                    code_end = Some(token.offset + token.text.len());
+                    terminated = kind == TokenKind::Semicolon;
+                }
+            }
+        }
+        if let (Some(end), Some(first)) = (code_end, pending.first()) {
+            comments.trailing_on_same_line = !source[end..first.offset].contains('\n');
+        }
+        comments.trailing = pending.into_iter().map(|c| c.text).collect();
+        Ok(comments)
+    }
+}
+
+struct Layout<'a> {
+    options: &'a FormatOptions,
+}
+
+impl Layout<'_> {
+    // Lays out `node` starting at `column`, with continuation lines indented
+    // by `indent`.
+    fn expr(&self, node: &Node, position: Position, column: usize, indent: usize) -> String {
+        let flat = flat(node, position);
+        // Leave room for the statement's `;`.
+        let reserved = usize::from(position == Position::Top);
+        if column + flat.len() + reserved <= self.options.max_width {
+            return flat;
+        }
+
+        // Literals, negated or not, can't be broken.
+        let negated = matches!(node, Node::UnaryExpr { child, .. } if child.is_literal());
+        if node.is_literal() || negated {
+            return flat;
+        }
+        if node.needs_parens(position) {
+            let inner = indent + self.options.indent_width;
+            return format!(
+                "(\n{}{}\n{})",
+                " ".repeat(inner),
+                self.expr(node, Position::Top, inner, inner),
+                " ".repeat(indent)
+            );
+        }
+        match node {
+            Node::Int(_) | Node::Float(_) => unreachable!("literals are laid out flat"),
+            Node::UnaryExpr { op, child } => {
+                let child = self.expr(child, Position::UnaryOperand, column + 1, indent);
+                format!("{}{}", op, child)
+            }
+            Node::BinaryExpr { .. } => {
+                // Break the whole left-leaning chain, one operator per line.
+                let mut chain = Vec::new();
+                let mut first = node;
+                while let Node::BinaryExpr { op, lhs, rhs } = first {
+                    chain.push((*op, rhs.as_ref()));
+                    first = lhs;
+                    if lhs.needs_parens(Position::Lhs(*op)) {
+                        break;
+                    }
+                }
+                let (first_op, _) = chain[chain.len() - 1];
+                let continuation = indent + self.options.indent_width;
+                let mut out = self.expr(first, Position::Lhs(first_op), column, indent);
+                for (op, rhs) in chain.into_iter().rev() {
+                    let rhs_column = continuation + op.to_string().len() + 1;
+                    out.push('\n');
+                    out.push_str(&" ".repeat(continuation));
+                    out.push_str(&format!(
+                        "{} {}",
+                        op,
+                        self.expr(rhs, Position::Rhs(op), rhs_column, continuation)
+                    ));
+                }
+                out
+            }
+        }
+    }
+}
+
+fn flat(node: &Node, position: Position) -> String {
+    node.lossless_at(position).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spacing_and_parentheses() {
+        assert_eq!(format_source("1+2").unwrap(), "1 + 2;\n");
+        assert_eq!(format_source("((1)+(2*3));").unwrap(), "1 + (2 * 3);\n");
+        assert_eq!(format_source("- ( 1 +2)").unwrap(), "-(1 + 2);\n");
+        assert_eq!(format_source("5.0 * 2.50").unwrap(), "5.0 * 2.5;\n");
+        assert_eq!(format_source("(-5) - (-0.0)").unwrap(), "(-5) - (-0.0);\n");
+        assert_eq!(
+            format_source("1 - ( - 2147483648 )").unwrap(),
+            "1 - (-2147483648);\n"
+        );
+        assert_eq!(format_source("1 - (-(2))").unwrap(), "1 - (-(2));\n");
+    }
+
+    #[test]
+    fn test_comments() {
+        let source = "// leading\n1 +  // inside\n 2; // trailing\n// after\n";
+        assert_eq!(
+            format_source(source).unwrap(),
+            "// leading\n// inside\n1 + 2; // trailing\n// after\n"
+        );
+        assert_eq!(
+            format_source("1\n// own line").unwrap(),
+            "1;\n// own line\n"
+        );
+    }
+
+    #[test]
+    fn test_breaks_long_expressions() {
+        let options = FormatOptions {
+            max_width: 16,
+            indent_width: 4,
+        };
+        assert_eq!(
+            format_source_with("1 + 2 + 3 + (4 * 5 * 6 - 7)", &options).unwrap(),
+            "1\n    + 2\n    + 3\n    + (\n        4\n            * 5\n            * 6\n            - 7\n    );\n"
+        );
+    }
+
+    #[test]
+    fn test_idempotent() {
+        let options = FormatOptions {
+            max_width: 20,
+            indent_width: 4,
+        };
+        let source = "// c\n-(10 + 20) * (30 - (40 / 50.5)) + 60 - (70 + 80 * 90) // t";
+        let once = format_source_with(source, &options).unwrap();
+        assert_eq!(format_source_with(&once, &options).unwrap(), once);
+        assert_eq!(
+            parser::parse(&once).unwrap(),
+            parser::parse(source).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rejects_input_after_terminator() {
+        assert!(format_source("1; 2").is_err());
+        assert!(format_source("1; // fine").is_ok());
+    }
+}
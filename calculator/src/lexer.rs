@@ -0,0 +1,206 @@
+use crate::ast::Operator;
+use anyhow::{Result, bail};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    Int,
+    Float,
+    Operator(Operator),
+    LParen,
+    RParen,
+    Semicolon,
+    // Only produced by `Lexer::with_comments`.
+    Comment,
+    Eof,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    pub offset: usize,
+}
+
+// Tokens are produced on demand: like the pest grammar, anything after the
+// terminating `;` is never looked at, so it must not cause a lexing error.
+#[derive(Clone)]
+pub struct Lexer<'a> {
+    source: &'a str,
+    pos: usize,
+    keep_comments: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            pos: 0,
+            keep_comments: false,
+        }
+    }
+
+    // Emits `//` comments as tokens instead of skipping them, for tools such
+    // as the formatter that need to preserve them.
+    pub fn with_comments(source: &'a str) -> Self {
+        Self {
+            keep_comments: true,
+            ..Self::new(source)
+        }
+    }
+
+    pub fn next_token(&mut self) -> Result<Token<'a>> {
+        let bytes = self.source.as_bytes();
+        loop {
+            self.skip_whitespace();
+            if !self.source[self.pos..].starts_with("//") {
+                break;
+            }
+            let start = self.pos;
+            self.pos = self.source[start..]
+                .find('\n')
+                .map_or(self.source.len(), |i| start + i);
+            if self.keep_comments {
+                return Ok(self.token(TokenKind::Comment, start));
+            }
+        }
+        let start = self.pos;
+        let Some(&byte) = bytes.get(start) else {
+            return Ok(self.token(TokenKind::Eof, start));
+        };
+
+        let kind = match byte {
+            b'+' => TokenKind::Operator(Operator::Plus),
+            b'-' => TokenKind::Operator(Operator::Minus),
+            b'*' => TokenKind::Operator(Operator::Multiply),
+            b'/' => TokenKind::Operator(Operator::Divide),
+            b'(' => TokenKind::LParen,
+            b')' => TokenKind::RParen,
+            b';' => TokenKind::Semicolon,
+            b'0'..=b'9' => return Ok(self.number()),
+            _ => {
+                let ch = self.source[start..].chars().next().unwrap();
+                bail!("unexpected character {:?} at {}", ch, self.location(start));
+            }
+        };
+        self.pos += 1;
+        Ok(self.token(kind, start))
+    }
+
+    pub fn location(&self, offset: usize) -> String {
+        let before = &self.source[..offset];
+        let line = before.matches('\n').count() + 1;
+        let col = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        format!("{}:{}", line, col)
+    }
+
+    fn number(&mut self) -> Token<'a> {
+        let bytes = self.source.as_bytes();
+        let start = self.pos;
+        self.skip_digits();
+        // `1.` is an Int followed by a stray `.`, matching the grammar's
+        // requirement of digits on both sides of the point.
+        let has_fraction = bytes.get(self.pos) == Some(&b'.')
+            && bytes.get(self.pos + 1).is_some_and(u8::is_ascii_digit);
+        if has_fraction {
+            self.pos += 1;
+            self.skip_digits();
+            self.token(TokenKind::Float, start)
+        } else {
+            self.token(TokenKind::Int, start)
+        }
+    }
+
+    fn skip_digits(&mut self) {
+        let bytes = self.source.as_bytes();
+        while bytes.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let bytes = self.source.as_bytes();
+        while matches!(bytes.get(self.pos), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            self.pos += 1;
+        }
+    }
+
+    fn token(&self, kind: TokenKind, start: usize) -> Token<'a> {
+        Token {
+            kind,
+            text: &self.source[start..self.pos],
+            offset: start,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        let mut lexer = Lexer::new(source);
+        let mut out = Vec::new();
+        loop {
+            let token = lexer.next_token().unwrap();
+            out.push(token.kind);
+            if token.kind == TokenKind::Eof {
+                return out;
+            }
+        }
+    }
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(
+            kinds(" 1 +\t2.5*(3)/\n-4;"),
+            vec![
+                TokenKind::Int,
+                TokenKind::Operator(Operator::Plus),
+                TokenKind::Float,
+                TokenKind::Operator(Operator::Multiply),
+                TokenKind::LParen,
+                TokenKind::Int,
+                TokenKind::RParen,
+                TokenKind::Operator(Operator::Divide),
+                TokenKind::Operator(Operator::Minus),
+                TokenKind::Int,
+                TokenKind::Semicolon,
+                TokenKind::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(
+            kinds("// leading\n1 // trailing"),
+            vec![TokenKind::Int, TokenKind::Eof]
+        );
+        let mut lexer = Lexer::with_comments("1 // trailing\n");
+        lexer.next_token().unwrap();
+        let comment = lexer.next_token().unwrap();
+        assert_eq!(
+            (comment.kind, comment.text),
+            (TokenKind::Comment, "// trailing")
+        );
+        assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Eof);
+    }
+
+    #[test]
+    fn test_number_text() {
+        let mut lexer = Lexer::new("12.50 7.");
+        assert_eq!(lexer.next_token().unwrap().text, "12.50");
+        let int = lexer.next_token().unwrap();
+        assert_eq!((int.kind, int.text), (TokenKind::Int, "7"));
+        assert!(lexer.next_token().is_err());
+    }
+
+    #[test]
+    fn test_error_location() {
+        let mut lexer = Lexer::new("1 +\n  $");
+        lexer.next_token().unwrap();
+        lexer.next_token().unwrap();
+        let err = lexer.next_token().unwrap_err();
+        assert_eq!(err.to_string(), "unexpected character '$' at 2:3");
+    }
+}
@@ -1,11 +1,20 @@
 #![allow(unused)]
 use crate::ast::Node;
+use crate::optimizer::OptLevel;
 use anyhow::Result;
 
-mod ast;
+pub mod ast;
 pub mod compiler;
-mod parser;
-mod primitive;
+mod descent;
+pub mod dump;
+pub mod format;
+mod lexer;
+pub mod optimizer;
+pub mod parser;
+pub mod primitive;
+mod sexpr;
+#[cfg(test)]
+mod testing;
 
 pub trait Compile {
     type Output;
@@ -16,4 +25,23 @@ pub trait Compile {
         let ast = parser::parse(source)?;
         Ok(Self::from_ast(ast))
     }
+
+    fn from_ast_with(ast: Vec<Node>, level: OptLevel) -> Self::Output {
+        Self::from_ast(optimizer::optimize(ast, level))
+    }
+
+    fn from_source_with(source: &str, level: OptLevel) -> Result<Self::Output> {
+        Self::from_source_with_depth(source, level, parser::MAX_DEPTH)
+    }
+
+    // Like `from_source_with`, but fails with `ResourceExhausted::Depth` if
+    // parentheses are nested more than `max_depth` deep.
+    fn from_source_with_depth(
+        source: &str,
+        level: OptLevel,
+        max_depth: usize,
+    ) -> Result<Self::Output> {
+        let ast = parser::parse_with(source, max_depth)?;
+        Ok(Self::from_ast_with(ast, level))
+    }
 }
@@ -1,8 +1,19 @@
+use anyhow::{Context, Result, bail};
 use calculator::Compile;
+use calculator::ast::Node;
+use calculator::format::format_source;
+use calculator::optimizer::{self, OptLevel};
+use calculator::{dump, parser};
 use cfg_if::cfg_if;
+use std::io::Read;
+#[cfg(feature = "vm")]
+use std::io::{IsTerminal, Write};
+use std::str::FromStr;
 
 cfg_if! {
-    if #[cfg(feature = "vm")] {
+    if #[cfg(feature = "register-vm")] {
+        use calculator::compiler::register::machine::RegisterVM as Engine;
+    } else if #[cfg(feature = "vm")] {
         use calculator::compiler::vm::vm::VM as Engine;
     } else if #[cfg(feature = "jit")] {
         use calculator::compiler::jit::Jit as Engine;
@@ -11,14 +22,388 @@ cfg_if! {
     }
 }
 
+#[cfg(feature = "vm")]
+use calculator::compiler::vm::bytecode::{Bytecode, Interpreter as BytecodeCompiler};
+#[cfg(feature = "vm")]
+use calculator::compiler::vm::debugger::Debugger;
+#[cfg(feature = "vm")]
+use calculator::compiler::vm::gklc;
+#[cfg(feature = "vm")]
+use calculator::compiler::vm::peephole;
+#[cfg(feature = "vm")]
+use calculator::compiler::vm::vm::VM;
+
+const USAGE: &str = "Usage: calculator [-O<level>] [--from <format>] [--emit <format>] <filename>
+       calculator compile [-O<level>] [-o <output>] <filename>
+       calculator disasm [-O<level>] <filename>
+       calculator debug <filename>
+       calculator profile [-O<level>] [--folded <output>] <filename>
+       calculator fmt [--check] [<filename>...]
+
+Formats: ast-json, ast-sexpr, bytecode-json, bytecode-sexpr
+(the bytecode formats require the vm feature)
+`compile` writes <filename> with a .gklc extension unless -o is given;
+.gklc files are recognised and run directly
+`profile` prints counts and times per opcode, instruction class and
+instruction; --folded also writes folded stacks for flamegraph tools
+Levels: 0 (default), 1 folds constants, 2 also simplifies";
+
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: calculator <filename>");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+        Some("fmt") => fmt(&args[1..]),
+        Some("compile") => compile(&args[1..]),
+        Some("disasm") => disasm(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("profile") => profile(&args[1..]),
+        Some(_) => run(&args),
+    };
+    if let Err(err) = result {
+        eprintln!("error: {:#}", err);
         std::process::exit(1);
     }
-    println!(
-        "{:?}",
-        Engine::from_source(std::fs::read_to_string(&args[1]).unwrap().as_str()).unwrap()
-    )
+}
+
+fn level_flag(flag: &str) -> Result<OptLevel> {
+    let Ok(level) = flag[2..].parse() else {
+        bail!("unknown optimisation level {}\n{}", flag, USAGE);
+    };
+    Ok(level)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    AstJson,
+    AstSexpr,
+    BytecodeJson,
+    BytecodeSexpr,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ast-json" => Ok(Format::AstJson),
+            "ast-sexpr" => Ok(Format::AstSexpr),
+            "bytecode-json" => Ok(Format::BytecodeJson),
+            "bytecode-sexpr" => Ok(Format::BytecodeSexpr),
+            other => bail!("unknown format {}\n{}", other, USAGE),
+        }
+    }
+}
+
+enum Program {
+    // Kept as source so that bytecode compiled from it gets a source map.
+    Source(String),
+    Ast(Vec<Node>),
+    #[cfg(feature = "vm")]
+    Bytecode(Bytecode),
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let mut from = None;
+    let mut emit = None;
+    let mut filename = None;
+    let mut level = OptLevel::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" | "--emit" => {
+                let Some(format) = args.next() else {
+                    bail!("{} expects a format\n{}", arg, USAGE);
+                };
+                let format = Some(format.parse::<Format>()?);
+                if arg == "--from" {
+                    from = format;
+                } else {
+                    emit = format;
+                }
+            }
+            flag if flag.starts_with("-O") => level = level_flag(flag)?,
+            flag if flag.starts_with('-') => bail!("unknown flag {}\n{}", flag, USAGE),
+            _ if filename.is_some() => bail!("expected a single file\n{}", USAGE),
+            file => filename = Some(file),
+        }
+    }
+    let Some(filename) = filename else {
+        bail!("missing filename\n{}", USAGE);
+    };
+
+    let bytes = std::fs::read(filename)?;
+    let program = load(&bytes, from)?;
+    match emit {
+        Some(format) => print!("{}", emit_program(program, format, level)?),
+        None => execute(program, level)?,
+    }
+    Ok(())
+}
+
+fn load(bytes: &[u8], from: Option<Format>) -> Result<Program> {
+    #[cfg(feature = "vm")]
+    if gklc::is_gklc(bytes) {
+        return Ok(Program::Bytecode(Bytecode::read_from(&mut &bytes[..])?));
+    }
+    let source = std::str::from_utf8(bytes).context("input is not UTF-8")?;
+    let program = match from {
+        None => Program::Source(source.to_string()),
+        Some(Format::AstJson) => Program::Ast(dump::ast_from_json(source)?),
+        Some(Format::AstSexpr) => Program::Ast(dump::ast_from_sexpr(source)?),
+        #[cfg(feature = "vm")]
+        Some(Format::BytecodeJson) => Program::Bytecode(Bytecode::from_json(source)?),
+        #[cfg(feature = "vm")]
+        Some(Format::BytecodeSexpr) => Program::Bytecode(Bytecode::from_sexpr(source)?),
+        #[cfg(not(feature = "vm"))]
+        Some(Format::BytecodeJson | Format::BytecodeSexpr) => {
+            bail!("bytecode formats require the vm feature")
+        }
+    };
+    Ok(program)
+}
+
+fn emit_program(program: Program, format: Format, level: OptLevel) -> Result<String> {
+    match (program, format) {
+        (Program::Source(source), Format::AstJson | Format::AstSexpr) => {
+            emit_program(Program::Ast(parser::parse(&source)?), format, level)
+        }
+        (Program::Ast(ast), Format::AstJson) => {
+            Ok(dump::ast_to_json(&optimizer::optimize(ast, level))? + "\n")
+        }
+        (Program::Ast(ast), Format::AstSexpr) => {
+            dump::ast_to_sexpr(&optimizer::optimize(ast, level))
+        }
+        #[cfg(feature = "vm")]
+        (Program::Bytecode(_), Format::AstJson | Format::AstSexpr) => {
+            bail!("cannot recover an AST from bytecode")
+        }
+        #[cfg(feature = "vm")]
+        (program, Format::BytecodeJson | Format::BytecodeSexpr) => {
+            let bytecode = to_bytecode(program, level)?;
+            if format == Format::BytecodeJson {
+                Ok(bytecode.to_json()? + "\n")
+            } else {
+                bytecode.to_sexpr()
+            }
+        }
+        #[cfg(not(feature = "vm"))]
+        (_, Format::BytecodeJson | Format::BytecodeSexpr) => {
+            bail!("bytecode formats require the vm feature")
+        }
+    }
+}
+
+// Prints the program's value; errors while running fail the command like
+// any other.
+fn execute(program: Program, level: OptLevel) -> Result<()> {
+    let value = match program {
+        Program::Source(source) => Engine::from_source_with(&source, level)??,
+        Program::Ast(ast) => Engine::from_ast_with(ast, level)?,
+        #[cfg(feature = "vm")]
+        Program::Bytecode(bytecode) => VM::execute(optimize_bytecode(bytecode, level)?)?,
+    };
+    println!("{:?}", value);
+    Ok(())
+}
+
+#[cfg(feature = "vm")]
+fn to_bytecode(program: Program, level: OptLevel) -> Result<Bytecode> {
+    match program {
+        Program::Source(source) => BytecodeCompiler::from_source_mapped(&source, level),
+        Program::Ast(ast) => BytecodeCompiler::from_ast_with(ast, level),
+        Program::Bytecode(bytecode) => optimize_bytecode(bytecode, level),
+    }
+}
+
+#[cfg(feature = "vm")]
+fn optimize_bytecode(bytecode: Bytecode, level: OptLevel) -> Result<Bytecode> {
+    if level >= OptLevel::O1 {
+        peephole::optimize(&bytecode)
+    } else {
+        Ok(bytecode)
+    }
+}
+
+// Compiles a source file to a `.gklc` file that `run` executes directly.
+#[cfg(feature = "vm")]
+fn compile(args: &[String]) -> Result<()> {
+    let mut output = None;
+    let mut filename = None;
+    let mut level = OptLevel::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => {
+                let Some(file) = args.next() else {
+                    bail!("-o expects a filename\n{}", USAGE);
+                };
+                output = Some(std::path::PathBuf::from(file));
+            }
+            flag if flag.starts_with("-O") => level = level_flag(flag)?,
+            flag if flag.starts_with('-') => bail!("unknown flag {}\n{}", flag, USAGE),
+            _ if filename.is_some() => bail!("expected a single file\n{}", USAGE),
+            file => filename = Some(file),
+        }
+    }
+    let Some(filename) = filename else {
+        bail!("missing filename\n{}", USAGE);
+    };
+
+    let source = std::fs::read_to_string(filename)?;
+    let bytecode = BytecodeCompiler::from_source_mapped(&source, level)?;
+    let output = output.unwrap_or_else(|| std::path::Path::new(filename).with_extension("gklc"));
+    let mut file = std::fs::File::create(&output)?;
+    bytecode.write_to(&mut file)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "vm"))]
+fn compile(_: &[String]) -> Result<()> {
+    bail!("compile requires the vm feature")
+}
+
+// Prints the annotated listing of a source or .gklc file's bytecode.
+#[cfg(feature = "vm")]
+fn disasm(args: &[String]) -> Result<()> {
+    let mut filename = None;
+    let mut level = OptLevel::default();
+    for arg in args {
+        match arg.as_str() {
+            flag if flag.starts_with("-O") => level = level_flag(flag)?,
+            flag if flag.starts_with('-') => bail!("unknown flag {}\n{}", flag, USAGE),
+            _ if filename.is_some() => bail!("expected a single file\n{}", USAGE),
+            file => filename = Some(file),
+        }
+    }
+    let Some(filename) = filename else {
+        bail!("missing filename\n{}", USAGE);
+    };
+
+    let bytecode = to_bytecode(load(&std::fs::read(filename)?, None)?, level)?;
+    print!("{}", bytecode.disassemble()?);
+    Ok(())
+}
+
+#[cfg(not(feature = "vm"))]
+fn disasm(_: &[String]) -> Result<()> {
+    bail!("disasm requires the vm feature")
+}
+
+// Steps through a source or .gklc file's bytecode, reading commands from
+// stdin. Offsets are the ones `disasm` prints.
+#[cfg(feature = "vm")]
+fn debug(args: &[String]) -> Result<()> {
+    let [filename] = args else {
+        bail!("expected a single file\n{}", USAGE);
+    };
+    let bytecode = to_bytecode(load(&std::fs::read(filename)?, None)?, OptLevel::O0)?;
+    let mut debugger = Debugger::new(bytecode)?;
+    let mut stdout = std::io::stdout();
+    debugger.show(&mut stdout)?;
+    let interactive = std::io::stdin().is_terminal();
+    loop {
+        if interactive {
+            print!("(debug) ");
+            stdout.flush()?;
+        }
+        let Some(line) = std::io::stdin().lines().next().transpose()? else {
+            return Ok(());
+        };
+        match debugger.command(&line, &mut stdout) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(err) => println!("error: {:#}", err),
+        }
+    }
+}
+
+#[cfg(not(feature = "vm"))]
+fn debug(_: &[String]) -> Result<()> {
+    bail!("debug requires the vm feature")
+}
+
+// Runs a source or .gklc file's bytecode under the profiler.
+#[cfg(feature = "vm")]
+fn profile(args: &[String]) -> Result<()> {
+    let mut folded = None;
+    let mut filename = None;
+    let mut level = OptLevel::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--folded" => {
+                let Some(file) = args.next() else {
+                    bail!("--folded expects a filename\n{}", USAGE);
+                };
+                folded = Some(file);
+            }
+            flag if flag.starts_with("-O") => level = level_flag(flag)?,
+            flag if flag.starts_with('-') => bail!("unknown flag {}\n{}", flag, USAGE),
+            _ if filename.is_some() => bail!("expected a single file\n{}", USAGE),
+            file => filename = Some(file),
+        }
+    }
+    let Some(filename) = filename else {
+        bail!("missing filename\n{}", USAGE);
+    };
+
+    let bytecode = to_bytecode(load(&std::fs::read(filename)?, None)?, level)?;
+    let mut vm = VM::new(bytecode)?;
+    let profile = vm.profile()?;
+    println!("result {:?}\n", vm.result());
+    print!("{}", profile.table());
+    if let Some(folded) = folded {
+        std::fs::write(folded, profile.folded())?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "vm"))]
+fn profile(_: &[String]) -> Result<()> {
+    bail!("profile requires the vm feature")
+}
+
+// Formats files in place, or stdin to stdout when no files are given. With
+// `--check` nothing is written and the exit status reports unformatted input.
+fn fmt(args: &[String]) -> Result<()> {
+    let check = args.iter().any(|arg| arg == "--check");
+    let files: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if let Some(flag) = files.iter().find(|arg| arg.starts_with('-')) {
+        bail!("unknown flag {}\n{}", flag, USAGE);
+    }
+
+    if files.is_empty() {
+        let mut source = String::new();
+        std::io::stdin().read_to_string(&mut source)?;
+        let formatted = format_source(&source)?;
+        if check {
+            if formatted != source {
+                bail!("<stdin> is not formatted");
+            }
+        } else {
+            print!("{}", formatted);
+        }
+        return Ok(());
+    }
+
+    let mut unformatted = Vec::new();
+    for file in files {
+        let source = std::fs::read_to_string(file)?;
+        let formatted = format_source(&source).with_context(|| file.clone())?;
+        if formatted == source {
+            continue;
+        }
+        if check {
+            unformatted.push(file.as_str());
+        } else {
+            std::fs::write(file, formatted)?;
+        }
+    }
+    if !unformatted.is_empty() {
+        bail!("not formatted: {}", unformatted.join(", "));
+    }
+    Ok(())
 }
@@ -0,0 +1,104 @@
+// Evaluates subtrees made only of literals, using the runtime's own
+// `PrimitiveType` arithmetic so that overflow wraps exactly as it would when
+// run. Integer division by zero is left for the runtime to report.
+use crate::ast::{Fold, Node, Operator};
+use crate::primitive::PrimitiveType;
+
+pub struct ConstantFolder;
+
+fn constant(node: &Node) -> Option<PrimitiveType> {
+    match node {
+        Node::Int(n) => Some((*n).into()),
+        Node::Float(f) => Some((*f).into()),
+        _ => None,
+    }
+}
+
+impl Fold for ConstantFolder {
+    fn fold_unary(&mut self, op: Operator, child: Node) -> Node {
+        match (op, constant(&child)) {
+            (Operator::Plus, Some(value)) => value.into(),
+            (Operator::Minus, Some(value)) => (-value).into(),
+            // Unary `*` and `/` have no meaning at runtime; keep them.
+            _ => Node::UnaryExpr {
+                op,
+                child: Box::new(child),
+            },
+        }
+    }
+
+    fn fold_binary(&mut self, op: Operator, lhs: Node, rhs: Node) -> Node {
+        if let (Some(a), Some(b)) = (constant(&lhs), constant(&rhs))
+            && let Some(value) = a.checked_binary(op, b)
+        {
+            return value.into();
+        }
+        Node::BinaryExpr {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::testing::{self, same_value};
+    use proptest::prelude::*;
+
+    fn fold(source: &str) -> Vec<Node> {
+        parse(source)
+            .unwrap()
+            .into_iter()
+            .map(|node| crate::ast::fold(&mut ConstantFolder, node))
+            .collect()
+    }
+
+    #[test]
+    fn test_folds_constants() {
+        assert_eq!(fold("(2 + 3) - (2 + 3)"), vec![Node::Int(0)]);
+        assert_eq!(fold("-(1.5 * 2)"), vec![Node::Float(-3.0)]);
+        assert_eq!(fold("1 + 2.5"), vec![Node::Float(3.5)]);
+        assert_eq!(fold("2147483647 + 1"), vec![Node::Int(i32::MIN)]);
+        assert_eq!(fold("1 / 0.0"), vec![Node::Float(f64::INFINITY)]);
+    }
+
+    #[test]
+    fn test_keeps_runtime_failures() {
+        assert_eq!(fold("4 / (1 - 1)"), parse("4 / 0").unwrap());
+        assert_eq!(fold("*(1 + 1)"), parse("*2").unwrap());
+    }
+
+    proptest! {
+        #[test]
+        fn test_folding_preserves_result(node in testing::program()) {
+            // Skip programs that fail at runtime.
+            prop_assume!(testing::eval(&node).is_some());
+            let folded = crate::ast::fold(&mut ConstantFolder, node.clone());
+            // Without a runtime failure every subtree is constant.
+            prop_assert!(matches!(folded, Node::Int(_) | Node::Float(_)));
+
+            let expected = testing::eval(&node).unwrap();
+            let result = testing::eval(&folded).unwrap();
+            prop_assert!(same_value(result, expected), "{:?} != {:?}", result, expected);
+            #[cfg(feature = "interpreter")]
+            {
+                use crate::Compile;
+                use crate::compiler::interpreter::Interpreter;
+                let expected = Interpreter::from_ast(vec![node.clone()]).unwrap();
+                let result = Interpreter::from_ast(vec![folded.clone()]).unwrap();
+                prop_assert!(same_value(result, expected), "{:?} != {:?}", result, expected);
+            }
+            #[cfg(feature = "vm")]
+            {
+                use crate::Compile;
+                use crate::compiler::vm::vm::VM;
+                let expected = VM::from_ast(vec![node]).unwrap();
+                let result = VM::from_ast(vec![folded]).unwrap();
+                prop_assert!(same_value(result, expected), "{:?} != {:?}", result, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+// AST optimisations run before lowering to any backend.
+use crate::ast::{Node, fold};
+use anyhow::{Result, bail};
+use std::str::FromStr;
+
+pub mod constant_folding;
+pub mod simplify;
+
+use constant_folding::ConstantFolder;
+use simplify::Simplifier;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum OptLevel {
+    // Run the program exactly as written.
+    #[default]
+    O0,
+    // Fold constant subexpressions.
+    O1,
+    // Also apply algebraic identities and reassociate Int chains.
+    O2,
+}
+
+impl FromStr for OptLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            other => bail!("unknown optimisation level {}", other),
+        }
+    }
+}
+
+// Every pass keeps the result of each statement bit-for-bit, and keeps any
+// operation that would fail at runtime.
+pub fn optimize(ast: Vec<Node>, level: OptLevel) -> Vec<Node> {
+    if level == OptLevel::O0 {
+        return ast;
+    }
+    ast.into_iter()
+        .map(|node| {
+            let node = fold(&mut ConstantFolder, node);
+            if level >= OptLevel::O2 {
+                fold(&mut Simplifier::default(), node)
+            } else {
+                node
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,363 @@
+// Algebraic identities and reassociation. A rule only fires when it
+// gives the same bits as the original for every value of `x` of the
+// statically known type: Int arithmetic wraps, and Floats follow IEEE 754,
+// so for example `x + 0` is kept for Floats because `-0.0 + 0` is `0.0`.
+use crate::ast::{Fold, Node, Operator};
+use crate::primitive::PrimitiveType;
+
+#[derive(Default)]
+pub struct Simplifier {
+    // The type of each node folded but not yet passed to its parent, which
+    // `fold` does in post-order, so no subtree is walked again to find it.
+    types: Vec<Option<Type>>,
+}
+
+// The type a node evaluates to; `None` if it doesn't evaluate at all.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Int,
+    Float,
+}
+
+// Whether `constant`, combined with an `x` of type `ty`, acts like `value`.
+// An Int constant is promoted when `x` is a Float; a Float constant would
+// turn an Int `x` into a Float, so it never matches one. Ints have a single
+// zero, so `0` matches both `0.0` and `-0.0`.
+fn is(constant: &Node, ty: Type, value: f64) -> bool {
+    match (constant, ty) {
+        (Node::Int(n), Type::Int) => f64::from(*n) == value,
+        (Node::Int(n), Type::Float) => f64::from(*n).to_bits() == value.to_bits(),
+        (Node::Float(f), Type::Float) => f.to_bits() == value.to_bits(),
+        _ => false,
+    }
+}
+
+fn binary(op: Operator, lhs: Node, rhs: Node) -> Node {
+    Node::BinaryExpr {
+        op,
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    }
+}
+
+impl Simplifier {
+    fn pop(&mut self) -> Option<Type> {
+        self.types.pop().expect("operand was folded")
+    }
+
+    // The simplified node, and its type.
+    fn simplify(
+        &mut self,
+        op: Operator,
+        lhs: Node,
+        lhs_type: Option<Type>,
+        rhs: Node,
+        rhs_type: Option<Type>,
+    ) -> (Node, Option<Type>) {
+        use Operator::*;
+
+        if let Some((outer, combined)) = reassociation(op, &lhs, lhs_type, &rhs) {
+            let mut lhs = lhs;
+            let Node::BinaryExpr { lhs: x, .. } = &mut lhs else {
+                unreachable!()
+            };
+            let int = Some(Type::Int);
+            return self.simplify(outer, Node::take(x), int, combined.into(), int);
+        }
+        let (Some(lhs_type), Some(rhs_type)) = (lhs_type, rhs_type) else {
+            return (binary(op, lhs, rhs), None);
+        };
+        let ty = match (lhs_type, rhs_type) {
+            (Type::Int, Type::Int) => Type::Int,
+            _ => Type::Float,
+        };
+        let node = match op {
+            // `x + 0`, `0 + x`, `x - 0`. For Floats the additive identity is
+            // `-0.0`, and `x - 0.0` is `x + -0.0`.
+            Plus if is(&rhs, lhs_type, -0.0) => lhs,
+            Plus if is(&lhs, rhs_type, -0.0) => rhs,
+            Minus if is(&rhs, lhs_type, 0.0) => lhs,
+            // `x * 1`, `1 * x`, `x / 1`.
+            Multiply | Divide if is(&rhs, lhs_type, 1.0) => lhs,
+            Multiply if is(&lhs, rhs_type, 1.0) => rhs,
+            // There is no `x * 2` to `x + x`: without variables, any `x` left
+            // after constant folding fails at runtime, so nothing is gained
+            // by evaluating it twice.
+            _ => binary(op, lhs, rhs),
+        };
+        (node, Some(ty))
+    }
+}
+
+// For `(x op c1) op c2` with everything an Int, the operator and constant
+// that combine `x` with both. Wrapping `+` and `*` are associative, so this
+// is exact; Float chains are left alone since every step rounds.
+fn reassociation(
+    op: Operator,
+    lhs: &Node,
+    lhs_type: Option<Type>,
+    rhs: &Node,
+) -> Option<(Operator, PrimitiveType)> {
+    use Operator::*;
+
+    let (
+        Node::BinaryExpr {
+            op: inner, rhs: c1, ..
+        },
+        Node::Int(c2),
+    ) = (lhs, rhs)
+    else {
+        return None;
+    };
+    let Node::Int(c1) = **c1 else {
+        return None;
+    };
+    // `c1` is an Int, so `x` is one exactly when `lhs` is.
+    if lhs_type != Some(Type::Int) {
+        return None;
+    }
+    let (c1, c2) = (PrimitiveType::Int(c1), PrimitiveType::Int(*c2));
+    // `x + c1 - c2` is `x + (c1 - c2)`, `x - c1 + c2` is `x - (c1 - c2)`.
+    match (inner, op) {
+        (Plus, Plus) => Some((Plus, c1 + c2)),
+        (Plus, Minus) => Some((Plus, c1 - c2)),
+        (Minus, Plus) => Some((Minus, c1 - c2)),
+        (Minus, Minus) => Some((Minus, c1 + c2)),
+        (Multiply, Multiply) => Some((Multiply, c1 * c2)),
+        _ => None,
+    }
+}
+
+impl Fold for Simplifier {
+    fn fold_int(&mut self, value: i32) -> Node {
+        self.types.push(Some(Type::Int));
+        Node::Int(value)
+    }
+
+    fn fold_float(&mut self, value: f64) -> Node {
+        self.types.push(Some(Type::Float));
+        Node::Float(value)
+    }
+
+    fn fold_unary(&mut self, op: Operator, mut child: Node) -> Node {
+        let child_type = self.pop();
+        match op {
+            Operator::Plus | Operator::Minus => self.types.push(child_type),
+            _ => self.types.push(None),
+        }
+        // Unary `+` is the identity, and `-(-x)` is `x` under wrapping.
+        if op == Operator::Plus {
+            return child;
+        }
+        if let (
+            Operator::Minus,
+            Node::UnaryExpr {
+                op: Operator::Minus,
+                child: inner,
+            },
+        ) = (op, &mut child)
+        {
+            return Node::take(inner);
+        }
+        Node::UnaryExpr {
+            op,
+            child: Box::new(child),
+        }
+    }
+
+    fn fold_binary(&mut self, op: Operator, lhs: Node, rhs: Node) -> Node {
+        let rhs_type = self.pop();
+        let lhs_type = self.pop();
+        let (node, ty) = self.simplify(op, lhs, lhs_type, rhs, rhs_type);
+        self.types.push(ty);
+        node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::fold;
+    use crate::optimizer::{OptLevel, optimize};
+    use crate::parser::parse;
+    use crate::testing::{self, same_value};
+    use proptest::prelude::*;
+
+    fn simplify(source: &str) -> Node {
+        fold(&mut Simplifier::default(), parse(source).unwrap().remove(0))
+    }
+
+    // Evaluates both trees, then runs them on every backend, and checks the
+    // results agree.
+    fn same_result(original: &Node, simplified: &Node) -> bool {
+        let expected = testing::eval(original).unwrap();
+        let result = testing::eval(simplified).unwrap();
+        let agree = same_value(result, expected);
+        #[cfg(feature = "interpreter")]
+        let agree = agree && {
+            use crate::Compile;
+            use crate::compiler::interpreter::Interpreter;
+            let expected = Interpreter::from_ast(vec![original.clone()]).unwrap();
+            let result = Interpreter::from_ast(vec![simplified.clone()]).unwrap();
+            same_value(result, expected)
+        };
+        #[cfg(feature = "vm")]
+        let agree = agree && {
+            use crate::Compile;
+            use crate::compiler::vm::vm::VM;
+            let expected = VM::from_ast(vec![original.clone()]).unwrap();
+            let result = VM::from_ast(vec![simplified.clone()]).unwrap();
+            same_value(result, expected)
+        };
+        #[cfg(feature = "register-vm")]
+        let agree = agree && {
+            use crate::Compile;
+            use crate::compiler::register::machine::RegisterVM;
+            let expected = RegisterVM::from_ast(vec![original.clone()]).unwrap();
+            let result = RegisterVM::from_ast(vec![simplified.clone()]).unwrap();
+            same_value(result, expected)
+        };
+        // The JIT only compiles Int literals.
+        #[cfg(feature = "jit")]
+        let agree = agree
+            && (has_float(original) || has_float(simplified) || {
+                use crate::Compile;
+                use crate::compiler::jit::Jit;
+                let expected = Jit::from_ast(vec![original.clone()]).unwrap();
+                let result = Jit::from_ast(vec![simplified.clone()]).unwrap();
+                result == expected
+            });
+        agree
+    }
+
+    #[cfg(feature = "jit")]
+    fn has_float(node: &Node) -> bool {
+        match node {
+            Node::Int(_) => false,
+            Node::Float(_) => true,
+            Node::UnaryExpr { child, .. } => has_float(child),
+            Node::BinaryExpr { lhs, rhs, .. } => has_float(lhs) || has_float(rhs),
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let cases = [
+            ("7 + 0", "7"),
+            ("0 + 7", "7"),
+            ("7 - 0", "7"),
+            ("1.5 + (-0.0)", "1.5"),
+            ("(-0.0) + 1.5", "1.5"),
+            ("1.5 - 0", "1.5"),
+            ("7 * 1", "7"),
+            ("1 * 1.5", "1.5"),
+            ("7 / 1", "7"),
+            ("1.5 / 1.0", "1.5"),
+            ("(1 / 0) * 2", "(1 / 0) * 2"),
+            ("-(-(7))", "7"),
+            ("+7", "7"),
+            ("7 + 1 - 3", "7 + (-2)"),
+            ("7 - 1 + 3", "7 - (-2)"),
+            ("7 * 3 * 5", "7 * 15"),
+            ("7 + 1 - 3 + 2", "7"),
+            ("1 / 0 + 1 + 2", "1 / 0 + 3"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(simplify(source), parse(expected).unwrap()[0], "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_rules_respecting_types() {
+        // Each of these would change the value for some `x`, or turn an Int
+        // into a Float.
+        for source in [
+            "1.5 + 0",
+            "0.0 + 1.5",
+            "7 + (-0.0)",
+            "1.5 - (-0.0)",
+            "7 * 1.0",
+            "7 / 1.0",
+            "1.5 + 1 + 2",
+            "7 + 1 + 2.0",
+            "7 * 0",
+        ] {
+            assert_eq!(simplify(source), parse(source).unwrap()[0], "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_rules_on_edge_values() {
+        let templates = [
+            "x + 0",
+            "0 + x",
+            "x - 0",
+            "x + (-0.0)",
+            "(-0.0) + x",
+            "x - 0.0",
+            "x * 1",
+            "1 * x",
+            "x / 1",
+            "x * 1.0",
+            "x / 1.0",
+            "x * 2",
+            "2 * x",
+            "x * 2.0",
+            "-(-(x))",
+            "+x",
+            "x + 5 - 7",
+            "x - 5 + 7",
+            "x * 3 * 5",
+            "x + 2147483647 + 1",
+        ];
+        let values = [
+            0.into(),
+            (-1).into(),
+            i32::MAX.into(),
+            i32::MIN.into(),
+            0.0.into(),
+            (-0.0).into(),
+            1.5.into(),
+            f64::INFINITY.into(),
+            f64::NEG_INFINITY.into(),
+            f64::NAN.into(),
+        ]
+        .map(|value: PrimitiveType| Node::from(value).lossless().to_string());
+        for template in templates {
+            for value in &values {
+                let source = template.replace('x', value);
+                let original = parse(&source).unwrap().remove(0);
+                let simplified = fold(&mut Simplifier::default(), original.clone());
+                assert!(same_result(&original, &simplified), "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn test_optimize_levels() {
+        let ast = parse("(2 + 3) * (1 / 0) * 1").unwrap();
+        assert_eq!(optimize(ast.clone(), OptLevel::O0), ast);
+        assert_eq!(
+            optimize(ast.clone(), OptLevel::O1),
+            parse("5 * (1 / 0) * 1").unwrap()
+        );
+        assert_eq!(optimize(ast, OptLevel::O2), parse("5 * (1 / 0)").unwrap());
+    }
+
+    #[test]
+    fn test_long_chain() {
+        // Nothing folds, and Float chains aren't reassociated.
+        let ast = parse(&format!("1 / 0{}", " + 0.5".repeat(200_000))).unwrap();
+        assert!(optimize(ast.clone(), OptLevel::O2) == ast);
+    }
+
+    proptest! {
+        #[test]
+        fn test_simplify_preserves_result(node in testing::program()) {
+            // Skip programs that fail at runtime.
+            prop_assume!(testing::eval(&node).is_some());
+            let simplified = fold(&mut Simplifier::default(), node.clone());
+            prop_assert!(same_result(&node, &simplified), "{:?} => {:?}", node, simplified);
+        }
+    }
+}
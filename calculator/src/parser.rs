@@ -1,95 +1,216 @@
-use crate::ast::Node;
 use crate::ast::Operator;
-use anyhow::Result;
+use crate::ast::{Node, Span, Spans};
+use crate::ast::{parse_float_literal, parse_int_literal};
+use crate::compiler::limits::ResourceExhausted;
+use anyhow::{Result, bail};
+use cfg_if::cfg_if;
 use pest::Parser;
 use pest::iterators::{Pair, Pairs};
 
+pub use crate::descent::parse as parse_descent;
+pub use crate::descent::parse_spanned_with as parse_descent_spanned_with;
+pub use crate::descent::parse_with as parse_descent_with;
+
+// How deeply parentheses may be nested. Parsing, and printing the AST,
+// recurses once per level, so deeper input could overflow the native stack.
+// A chain like `1 + 2 + 3` nests the AST one level deeper per operator without
+// parentheses, but passes over the AST keep their own stack, so only memory
+// bounds its length.
+pub const MAX_DEPTH: usize = 1000;
+
 #[derive(pest_derive::Parser)]
 #[grammar = "grammar.pest"]
 pub struct CalcParser;
 
 pub fn parse(source: &str) -> Result<Vec<Node>> {
+    parse_with(source, MAX_DEPTH)
+}
+
+// Like `parse`, but fails with `ResourceExhausted::Depth` if parentheses are
+// nested more than `max_depth` deep.
+pub fn parse_with(source: &str, max_depth: usize) -> Result<Vec<Node>> {
+    cfg_if! {
+        if #[cfg(feature = "descent")] {
+            parse_descent_with(source, max_depth)
+        } else {
+            parse_pest_with(source, max_depth)
+        }
+    }
+}
+
+// Like `parse`, but also returns the span of every node, in post-order.
+pub fn parse_spanned(source: &str) -> Result<(Vec<Node>, Vec<Span>)> {
+    parse_spanned_with(source, MAX_DEPTH)
+}
+
+pub fn parse_spanned_with(source: &str, max_depth: usize) -> Result<(Vec<Node>, Vec<Span>)> {
+    cfg_if! {
+        if #[cfg(feature = "descent")] {
+            parse_descent_spanned_with(source, max_depth)
+        } else {
+            parse_pest_spanned_with(source, max_depth)
+        }
+    }
+}
+
+pub fn parse_pest(source: &str) -> Result<Vec<Node>> {
+    parse_pest_with(source, MAX_DEPTH)
+}
+
+pub fn parse_pest_with(source: &str, max_depth: usize) -> Result<Vec<Node>> {
+    Ok(parse_pest_spanned_with(source, max_depth)?.0)
+}
+
+pub fn parse_pest_spanned_with(source: &str, max_depth: usize) -> Result<(Vec<Node>, Vec<Span>)> {
+    check_depth(source, max_depth)?;
     let pairs = parse_calc(source);
     let pairs = pairs?;
     let mut nodes = Vec::new();
+    let mut spans = Spans::new(source);
     for pair in pairs {
         if let Rule::Expr = pair.as_rule() {
-            nodes.push(build_ast_from_expr(pair));
+            nodes.push(build_ast_from_expr(pair, &mut spans)?);
         }
     }
-    Ok(nodes)
+    Ok((nodes, spans.into_vec()))
 }
 
 pub fn parse_calc(source: &str) -> Result<Pairs<'_, Rule>> {
     Ok(CalcParser::parse(Rule::Program, source)?)
 }
 
-fn build_ast_from_expr(pair: Pair<Rule>) -> Node {
-    match pair.as_rule() {
-        Rule::Expr => build_ast_from_expr(pair.into_inner().next().unwrap()),
-        Rule::BinaryExpr => {
-            let mut pairs = pair.into_inner();
-
-            let first = pairs.next().unwrap();
-            // LHS can be UnaryExpr or TERM
-            let lhs = match first.as_rule() {
-                Rule::UnaryExpr => build_ast_from_unary_expr(first),
-                _ => build_ast_from_term(first),
-            };
-
-            let operator = pairs.next().unwrap();
-            let op = Operator::from(operator.as_str());
-            let rhs = build_ast_from_term(pairs.next().unwrap());
-            let mut out = Node::BinaryExpr {
-                lhs: Box::new(lhs),
-                op,
-                rhs: Box::new(rhs),
-            };
-            loop {
-                let pair_buf = pairs.next();
-                if let Some(pair) = pair_buf {
-                    let lhs = out;
-                    let op = Operator::from(pair.as_str());
-                    let rhs = build_ast_from_term(pairs.next().unwrap());
-                    out = Node::BinaryExpr {
-                        lhs: Box::new(lhs),
-                        op,
-                        rhs: Box::new(rhs),
-                    }
-                } else {
-                    return out;
+// pest recurses once per level of parentheses before `build_ast_from_expr`
+// sees the tree, so the depth is checked on the source first.
+fn check_depth(source: &str, max_depth: usize) -> Result<()> {
+    let mut depth = 0;
+    for line in source.lines() {
+        let code = line.split("//").next().unwrap_or_default();
+        for c in code.chars() {
+            match c {
+                '(' if depth == max_depth => {
+                    bail!(ResourceExhausted::Depth { max: max_depth })
                 }
+                '(' => depth += 1,
+                ')' => depth = usize::saturating_sub(depth, 1),
+                // Input after the `;` is ignored.
+                ';' => return Ok(()),
+                _ => {}
             }
         }
-        Rule::UnaryExpr => build_ast_from_unary_expr(pair),
-        other => panic!("Unknown rule for expr: {:?}", other),
     }
+    Ok(())
 }
 
-fn build_ast_from_unary_expr(pair: Pair<Rule>) -> Node {
+// Each build function records the span of every node it builds, after those
+// of the node's children.
+fn build_ast_from_expr(pair: Pair<Rule>, spans: &mut Spans) -> Result<Node> {
+    // From the `(` of a parenthesized first operand.
+    let start = pair.as_span().start();
+    let mut pairs = pair.into_inner();
+
+    let first = pairs.next().unwrap();
+    // LHS can be UnaryExpr or TERM
+    let mut out = match first.as_rule() {
+        Rule::UnaryExpr => build_ast_from_unary_expr(first, spans)?,
+        _ => build_ast_from_term(first, spans)?,
+    };
+    // `Term` is silent, so operands show up directly under `Expr`, each
+    // after its operator.
+    while let Some(pair) = pairs.next() {
+        let lhs = out;
+        let op = Operator::from(pair.as_str());
+        let term = pairs.next().unwrap();
+        let end = term_end(&term);
+        let rhs = build_ast_from_term(term, spans)?;
+        spans.push(start, end);
+        out = Node::BinaryExpr {
+            lhs: Box::new(lhs),
+            op,
+            rhs: Box::new(rhs),
+        }
+    }
+    Ok(out)
+}
+
+fn build_ast_from_unary_expr(pair: Pair<Rule>, spans: &mut Spans) -> Result<Node> {
+    let start = pair.as_span().start();
     let mut pairs = pair.into_inner();
     let operator = pairs.next().unwrap();
     let op = Operator::from(operator.as_str());
     let child = pairs.next().unwrap();
-    let child = Box::new(build_ast_from_term(child));
-    Node::UnaryExpr { op, child }
+    let end = term_end(&child);
+    let child = Box::new(build_ast_from_term(child, spans)?);
+    spans.push(start, end);
+    Ok(Node::UnaryExpr { op, child })
 }
 
-fn build_ast_from_term(pair: Pair<Rule>) -> Node {
+fn build_ast_from_term(pair: Pair<Rule>, spans: &mut Spans) -> Result<Node> {
+    let span = pair.as_span();
     match pair.as_rule() {
         Rule::Int => {
-            let int = pair.as_str().parse::<i32>().unwrap();
-            Node::Int(int)
+            let int = parse_int_literal(pair.as_str(), false)?;
+            spans.push(span.start(), span.end());
+            Ok(Node::Int(int))
         }
         Rule::Float => {
-            let float = pair.as_str().parse::<f64>().unwrap();
-            Node::Float(float)
+            let float = parse_float_literal(pair.as_str(), false)?;
+            spans.push(span.start(), span.end());
+            Ok(Node::Float(float))
         }
-        Rule::Expr => build_ast_from_expr(pair),
+        Rule::Expr => match negative_literal(&pair) {
+            Some(literal) => build_negative_literal(literal, spans),
+            None => build_ast_from_expr(pair, spans),
+        },
         other => panic!("unknown term {:?}", other),
     }
 }
 
+// Where an operand ends, including the `)` around it. `Term` is silent, so
+// there is no pair for the parentheses, but only whitespace and comments can
+// come between a parenthesized `Expr` and its `)`.
+fn term_end(term: &Pair<Rule>) -> usize {
+    let expr = term.as_span();
+    if term.as_rule() != Rule::Expr {
+        return expr.end();
+    }
+    let source = expr.get_input();
+    let mut rest = &source[expr.end()..];
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', '\r', '\n']);
+        match rest.strip_prefix("//") {
+            Some(comment) => rest = comment.find('\n').map_or("", |i| &comment[i..]),
+            None => break,
+        }
+    }
+    debug_assert!(rest.starts_with(')'));
+    source.len() - rest.len() + 1
+}
+
+// The `UnaryExpr` of a parenthesized `(-5)`, which is read as a negative
+// literal rather than a unary minus.
+fn negative_literal<'a>(expr: &Pair<'a, Rule>) -> Option<Pair<'a, Rule>> {
+    let mut pairs = expr.clone().into_inner();
+    let unary = pairs.next()?;
+    if unary.as_rule() != Rule::UnaryExpr || pairs.next().is_some() {
+        return None;
+    }
+    let mut inner = unary.clone().into_inner();
+    let is_minus = inner.next()?.as_str() == "-";
+    let is_number = matches!(inner.next()?.as_rule(), Rule::Int | Rule::Float);
+    (is_minus && is_number).then_some(unary)
+}
+
+fn build_negative_literal(unary: Pair<Rule>, spans: &mut Spans) -> Result<Node> {
+    let span = unary.as_span();
+    let digits = unary.into_inner().nth(1).unwrap();
+    let node = match digits.as_rule() {
+        Rule::Int => Node::Int(parse_int_literal(digits.as_str(), true)?),
+        _ => Node::Float(parse_float_literal(digits.as_str(), true)?),
+    };
+    spans.push(span.start(), span.end());
+    Ok(node)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -120,4 +241,69 @@ mod tests {
             }]
         )
     }
+
+    #[test]
+    fn test_max_depth() {
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(parse_pest(&nested(MAX_DEPTH)).unwrap(), vec![Node::Int(1)]);
+        let err = parse_pest(&nested(MAX_DEPTH + 1)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&ResourceExhausted::Depth { max: MAX_DEPTH })
+        );
+        assert!(parse_pest_with("(1) + ((2))", 1).is_err());
+        assert!(parse_pest_with("(1) + (2) // ((", 1).is_ok());
+    }
+
+    #[test]
+    fn test_long_chain() {
+        // Only parentheses count toward the depth, however deep a chain nests
+        // the AST.
+        let chain = format!("1{}", " + 1".repeat(200_000));
+        let ast = parse_pest_with(&chain, 1).unwrap();
+        assert_eq!(ast[0].size(), 400_001);
+        assert!(parse_pest_with("-(1 * 2) + (3 * 4)", 1).is_ok());
+        assert!(parse_pest_with("1 + (2 * (3 + 4))", 1).is_err());
+    }
+
+    #[test]
+    fn test_spans() {
+        let (_, spans) = parse_pest_spanned_with("-1 +\n (2.5 * 3)", MAX_DEPTH).unwrap();
+        let spans: Vec<_> = spans
+            .iter()
+            .map(|span| (span.start..span.end, span.to_string()))
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                (1..2, "1:2".to_string()),
+                (0..2, "1:1".to_string()),
+                (7..10, "2:3".to_string()),
+                (13..14, "2:9".to_string()),
+                (7..14, "2:3".to_string()),
+                (0..15, "1:1".to_string()),
+            ]
+        );
+
+        // Parentheses around an operand belong to the nodes it is part of.
+        let ranges = |source| {
+            let (_, spans) = parse_pest_spanned_with(source, MAX_DEPTH).unwrap();
+            spans
+                .iter()
+                .map(|span| span.start..span.end)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ranges("(1 + 2) * (3 // )\n) - 4"),
+            [1..2, 5..6, 1..6, 11..12, 0..19, 22..23, 0..23]
+        );
+        assert_eq!(ranges("-(1 + 2)"), [2..3, 6..7, 2..7, 0..8]);
+        assert_eq!(ranges("1 * (-2)"), [0..1, 5..7, 0..8]);
+    }
+
+    #[test]
+    fn test_parse_literal() {
+        assert_eq!(parse_pest("7").unwrap(), vec![Node::Int(7)]);
+        assert_eq!(parse_pest("(2.5)").unwrap(), vec![Node::Float(2.5)]);
+    }
 }
@@ -1,11 +1,12 @@
-use crate::ast::Node;
+use crate::ast::{Node, Operator};
 use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
 use std::ops::{Add, Div, Mul, Neg, Sub};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum PrimitiveType {
     Int(i32),
-    Float(f64),
+    Float(#[serde(with = "crate::dump::json_float")] f64),
 }
 
 impl From<i32> for PrimitiveType {
@@ -25,20 +26,22 @@ impl Neg for PrimitiveType {
 
     fn neg(self) -> Self::Output {
         match self {
-            PrimitiveType::Int(n) => PrimitiveType::Int(-n),
+            PrimitiveType::Int(n) => PrimitiveType::Int(n.wrapping_neg()),
             PrimitiveType::Float(f) => PrimitiveType::Float(-f),
         }
     }
 }
 
+// Int arithmetic wraps on overflow in every build profile, matching the JIT.
+// Int division by zero still panics.
 macro_rules! impl_binary_op {
-    ($trait:ident, $method:ident, $op:tt) => {
+    ($trait:ident, $method:ident, $wrapping:ident, $op:tt) => {
         impl $trait for PrimitiveType {
             type Output = PrimitiveType;
 
             fn $method(self, rhs: Self) -> Self::Output {
                 match (self, rhs) {
-                    (PrimitiveType::Int(a), PrimitiveType::Int(b)) => PrimitiveType::Int(a $op b ),
+                    (PrimitiveType::Int(a), PrimitiveType::Int(b)) => PrimitiveType::Int(a.$wrapping(b)),
                     (PrimitiveType::Int(a), PrimitiveType::Float(b)) => PrimitiveType::Float(a as f64 $op b),
                     (PrimitiveType::Float(a), PrimitiveType::Int(b)) => PrimitiveType::Float(a $op b as f64),
                     (PrimitiveType::Float(a), PrimitiveType::Float(b)) => PrimitiveType::Float(a $op b),
@@ -48,10 +51,52 @@ macro_rules! impl_binary_op {
     };
 }
 
-impl_binary_op!(Add, add, +);
-impl_binary_op!(Sub, sub, -);
-impl_binary_op!(Mul, mul, *);
-impl_binary_op!(Div, div, /);
+impl_binary_op!(Add, add, wrapping_add, +);
+impl_binary_op!(Sub, sub, wrapping_sub, -);
+impl_binary_op!(Mul, mul, wrapping_mul, *);
+impl_binary_op!(Div, div, wrapping_div, /);
+
+impl PrimitiveType {
+    // Applies a binary operator like the runtime does, or returns None where
+    // the runtime would fail, i.e. on Int division by zero.
+    pub fn checked_binary(self, op: Operator, rhs: Self) -> Option<Self> {
+        match op {
+            Operator::Plus => Some(self + rhs),
+            Operator::Minus => Some(self - rhs),
+            Operator::Multiply => Some(self * rhs),
+            Operator::Divide => match (self, rhs) {
+                (PrimitiveType::Int(_), PrimitiveType::Int(0)) => None,
+                _ => Some(self / rhs),
+            },
+        }
+    }
+}
+
+impl From<PrimitiveType> for Node {
+    fn from(value: PrimitiveType) -> Self {
+        match value {
+            PrimitiveType::Int(n) => Node::Int(n),
+            PrimitiveType::Float(f) => Node::Float(f),
+        }
+    }
+}
+
+// Identifies a constant by its bits, so that `0.0` and `-0.0` stay apart
+// and identical NaNs are equal, for deduplicating constant tables.
+#[derive(PartialEq, Eq, Hash)]
+pub enum ConstantKey {
+    Int(i32),
+    Float(u64),
+}
+
+impl From<PrimitiveType> for ConstantKey {
+    fn from(value: PrimitiveType) -> Self {
+        match value {
+            PrimitiveType::Int(n) => ConstantKey::Int(n),
+            PrimitiveType::Float(f) => ConstantKey::Float(f.to_bits()),
+        }
+    }
+}
 
 impl TryFrom<Node> for PrimitiveType {
     type Error = anyhow::Error;
@@ -64,3 +109,33 @@ impl TryFrom<Node> for PrimitiveType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_int_overflow_wraps() {
+        let max = PrimitiveType::Int(i32::MAX);
+        let min = PrimitiveType::Int(i32::MIN);
+        assert_eq!(max + 1.into(), min);
+        assert_eq!(min - 1.into(), max);
+        assert_eq!(max * 2.into(), (-2).into());
+        assert_eq!(min / (-1).into(), min);
+        assert_eq!(-min, min);
+    }
+
+    #[test]
+    fn test_checked_binary() {
+        let two = PrimitiveType::Int(2);
+        assert_eq!(two.checked_binary(Operator::Divide, 0.into()), None);
+        assert_eq!(
+            two.checked_binary(Operator::Divide, 0.0.into()),
+            Some(f64::INFINITY.into())
+        );
+        assert_eq!(
+            two.checked_binary(Operator::Minus, 0.5.into()),
+            Some(1.5.into())
+        );
+    }
+}
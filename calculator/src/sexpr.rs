@@ -0,0 +1,113 @@
+// Minimal S-expression reader and printer backing the `*-sexpr` dumps.
+use crate::compiler::limits::ResourceExhausted;
+use crate::parser::MAX_DEPTH;
+use anyhow::{Result, bail};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sexpr {
+    Atom(String),
+    List(Vec<Sexpr>),
+}
+
+impl Sexpr {
+    pub fn atom(text: impl Into<String>) -> Self {
+        Sexpr::Atom(text.into())
+    }
+
+    pub fn as_atom(&self) -> Result<&str> {
+        match self {
+            Sexpr::Atom(text) => Ok(text),
+            Sexpr::List(_) => bail!("expected an atom, found {}", self),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&[Sexpr]> {
+        match self {
+            Sexpr::List(items) => Ok(items),
+            Sexpr::Atom(_) => bail!("expected a list, found {}", self),
+        }
+    }
+}
+
+impl fmt::Display for Sexpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sexpr::Atom(text) => write!(f, "{}", text),
+            Sexpr::List(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+// Reads every top-level expression in `source`. `;` starts a line comment.
+// Lists may be nested `MAX_DEPTH` deep, as printing and dropping a `Sexpr`
+// recurse once per level.
+pub fn parse_all(source: &str) -> Result<Vec<Sexpr>> {
+    let mut stack: Vec<Vec<Sexpr>> = vec![Vec::new()];
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, ch)) = chars.next() {
+        match ch {
+            '(' if stack.len() > MAX_DEPTH => {
+                bail!(ResourceExhausted::Depth { max: MAX_DEPTH })
+            }
+            '(' => stack.push(Vec::new()),
+            ')' => {
+                if stack.len() == 1 {
+                    bail!("unbalanced ')' at byte {}", start);
+                }
+                let list = stack.pop().unwrap();
+                stack.last_mut().unwrap().push(Sexpr::List(list));
+            }
+            ';' => while chars.next_if(|(_, ch)| *ch != '\n').is_some() {},
+            ch if ch.is_whitespace() => {}
+            _ => {
+                let mut end = start + ch.len_utf8();
+                while let Some((i, ch)) =
+                    chars.next_if(|(_, ch)| !ch.is_whitespace() && !"();".contains(*ch))
+                {
+                    end = i + ch.len_utf8();
+                }
+                stack
+                    .last_mut()
+                    .unwrap()
+                    .push(Sexpr::atom(&source[start..end]));
+            }
+        }
+    }
+    if stack.len() > 1 {
+        bail!(
+            "unbalanced '(': missing {} closing parentheses",
+            stack.len() - 1
+        );
+    }
+    Ok(stack.pop().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_print() {
+        let items = parse_all("(+ (- 11) 2) ; comment\n42 ()").unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].to_string(), "(+ (- 11) 2)");
+        assert_eq!(items[1], Sexpr::atom("42"));
+        assert_eq!(items[2], Sexpr::List(vec![]));
+    }
+
+    #[test]
+    fn test_unbalanced() {
+        assert!(parse_all("(+ 1 2").is_err());
+        assert!(parse_all("1)").is_err());
+    }
+}
@@ -0,0 +1,122 @@
+// Shared proptest strategies and comparisons for tests across the crate.
+use crate::ast::{Node, Operator};
+use crate::primitive::PrimitiveType;
+use proptest::prelude::*;
+
+pub fn operator() -> impl Strategy<Value = Operator> + Clone {
+    prop_oneof![
+        Just(Operator::Plus),
+        Just(Operator::Minus),
+        Just(Operator::Multiply),
+        Just(Operator::Divide),
+    ]
+}
+
+fn tree(
+    leaf: impl Strategy<Value = Node> + 'static,
+    unary: impl Strategy<Value = Operator> + Clone + 'static,
+) -> impl Strategy<Value = Node> {
+    leaf.prop_recursive(8, 64, 2, move |inner| {
+        prop_oneof![
+            (unary.clone(), inner.clone()).prop_map(|(op, child)| Node::UnaryExpr {
+                op,
+                child: Box::new(child)
+            }),
+            (operator(), inner.clone(), inner).prop_map(|(op, lhs, rhs)| {
+                Node::BinaryExpr {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                }
+            }),
+        ]
+    })
+}
+
+// Any tree the AST can represent, including ones no backend can run, except
+// for NaN literals, which have no spelling in source.
+pub fn node() -> impl Strategy<Value = Node> {
+    use prop::num::f64::{INFINITE, NEGATIVE, NORMAL, POSITIVE, SUBNORMAL, ZERO};
+    let leaf = prop_oneof![
+        any::<i32>().prop_map(Node::Int),
+        (POSITIVE | NEGATIVE | NORMAL | SUBNORMAL | ZERO | INFINITE).prop_map(Node::Float),
+    ];
+    tree(leaf, operator())
+}
+
+// Trees the backends accept: only `+` and `-` as unary operators, and small
+// literals so that overflow, zero and the identities in the optimiser show up.
+pub fn program() -> impl Strategy<Value = Node> {
+    let leaf = prop_oneof![
+        (-3..=3).prop_map(Node::Int),
+        any::<i32>().prop_map(Node::Int),
+        prop_oneof![
+            Just(0.0),
+            Just(-0.0),
+            Just(1.0),
+            Just(0.5),
+            Just(f64::INFINITY),
+            Just(f64::NAN),
+        ]
+        .prop_map(Node::Float),
+        any::<f64>().prop_map(Node::Float),
+    ];
+    tree(
+        leaf,
+        prop_oneof![Just(Operator::Plus), Just(Operator::Minus)],
+    )
+}
+
+// Reference evaluation, or None where a backend fails, i.e. on Int division
+// by zero.
+pub fn eval(node: &Node) -> Option<PrimitiveType> {
+    match node {
+        Node::Int(n) => Some((*n).into()),
+        Node::Float(f) => Some((*f).into()),
+        Node::UnaryExpr { op, child } => match op {
+            Operator::Plus => eval(child),
+            Operator::Minus => eval(child).map(|value| -value),
+            _ => None,
+        },
+        Node::BinaryExpr { op, lhs, rhs } => eval(lhs)?.checked_binary(*op, eval(rhs)?),
+    }
+}
+
+// Like `==`, but tells `0.0` from `-0.0` and treats all NaNs as equal.
+pub fn same_value(a: PrimitiveType, b: PrimitiveType) -> bool {
+    match (a, b) {
+        (PrimitiveType::Int(a), PrimitiveType::Int(b)) => a == b,
+        (PrimitiveType::Float(a), PrimitiveType::Float(b)) => {
+            a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+        }
+        _ => false,
+    }
+}
+
+// Like `==`, but tells `0.0` from `-0.0` and treats NaNs of the same sign
+// as equal.
+pub fn identical(a: &Node, b: &Node) -> bool {
+    match (a, b) {
+        (Node::Int(a), Node::Int(b)) => a == b,
+        (Node::Float(a), Node::Float(b)) => {
+            a.to_bits() == b.to_bits()
+                || (a.is_nan() && b.is_nan() && a.is_sign_negative() == b.is_sign_negative())
+        }
+        (
+            Node::UnaryExpr { op, child },
+            Node::UnaryExpr {
+                op: other_op,
+                child: other_child,
+            },
+        ) => op == other_op && identical(child, other_child),
+        (
+            Node::BinaryExpr { op, lhs, rhs },
+            Node::BinaryExpr {
+                op: other_op,
+                lhs: other_lhs,
+                rhs: other_rhs,
+            },
+        ) => op == other_op && identical(lhs, other_lhs) && identical(rhs, other_rhs),
+        _ => false,
+    }
+}
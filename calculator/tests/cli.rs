@@ -0,0 +1,41 @@
+// Runs the `main` binary on small programs and checks what it prints.
+use std::process::{Command, Output};
+
+fn run(name: &str, source: &str) -> Output {
+    let path = std::env::temp_dir().join(format!(
+        "calculator-cli-{}-{}.calc",
+        std::process::id(),
+        name
+    ));
+    std::fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_main"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn test_prints_the_value() {
+    let output = run("value", "1 + 2");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Int(3)\n");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn test_fails_on_runtime_errors() {
+    for (name, source, message) in [
+        ("division", "1 + 4 / 0", "division by zero\n"),
+        ("unary", "*5", "`*` is not a unary operator\n"),
+    ] {
+        let output = run(name, source);
+        assert_eq!(output.status.code(), Some(1), "{}", source);
+        assert!(output.stdout.is_empty(), "{}", source);
+        // The stack VM puts the error's place in the source first.
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.starts_with("error: "), "{}", stderr);
+        assert!(stderr.ends_with(message), "{}", stderr);
+    }
+}